@@ -0,0 +1,26 @@
+use serde::{Serialize, Deserialize};
+
+/// A single message from the chat dataset, as written by `parquet_to_json.py`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatRecord {
+    pub content: String,
+    pub role: String,
+    /// The conversation the message belongs to. Missing from datasets written before turns were kept.
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    /// The position of the message within its conversation
+    #[serde(default)]
+    pub turn: Option<usize>,
+}
+
+/// Load every message in the chat dataset
+pub fn load_chat_records(json_path: &str) -> Vec<ChatRecord> {
+    let file = std::fs::File::open(json_path).unwrap();
+    let reader = std::io::BufReader::new(file);
+    serde_json::from_reader(reader).unwrap()
+}
+
+/// Load the raw content of every message in the chat dataset
+pub fn load_messages(json_path: &str) -> Vec<String> {
+    load_chat_records(json_path).into_iter().map(|record| record.content).collect()
+}
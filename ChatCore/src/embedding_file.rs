@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use serde::{Serialize, Deserialize};
+
+// Defines the format of the embedding file produced by WordEmbeddings
+#[derive(Serialize, Deserialize)]
+pub struct EmbeddingFile {
+    pub data: HashMap<String, Vec<f32>>,
+}
+
+/// Load word vectors from an embedding file
+pub fn load_embeddings(file_name: &str) -> HashMap<String, Vec<f32>> {
+    let mut file = File::open(file_name).expect("Failed to open file");
+    let mut serialized = String::new();
+    file.read_to_string(&mut serialized).expect("Failed to read file");
+
+    // Deserialize the embeddings into a HashMap.
+    let deserialized: EmbeddingFile = serde_json::from_str(&serialized).unwrap();
+    deserialized.data
+}
+
+/// Save word vectors to an embedding file
+pub fn save_embeddings(file_name: &str, data: HashMap<String, Vec<f32>>) {
+    let serialized = serde_json::to_string(&EmbeddingFile { data }).unwrap();
+    let mut file = File::create(file_name).expect("Unable to create file");
+    file.write_all(serialized.as_bytes()).expect("Unable to write");
+}
@@ -0,0 +1,5 @@
+pub mod dataset;
+pub mod embedding_file;
+pub mod normalise;
+pub mod stylometry;
+pub mod tokenizer;
\ No newline at end of file
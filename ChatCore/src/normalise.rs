@@ -0,0 +1,291 @@
+use serde::{Serialize, Deserialize};
+use unicode_normalization::UnicodeNormalization;
+
+/// Replaces URLs when `Normaliser::urls` is set
+pub const URL_TOKEN: &str = "<url>";
+/// Replaces email addresses when `Normaliser::emails` is set
+pub const EMAIL_TOKEN: &str = "<email>";
+/// Replaces numbers when `Normaliser::numbers` is set
+pub const NUMBER_TOKEN: &str = "<num>";
+/// Replaces each line break when `Normaliser::newline_tokens` is set
+pub const NEWLINE_TOKEN: &str = "<nl>";
+
+/// Tokens produced by the normaliser which must not be split any further
+pub const PLACEHOLDERS: [&str; 4] = [URL_TOKEN, EMAIL_TOKEN, NUMBER_TOKEN, NEWLINE_TOKEN];
+
+/// What to do with characters which are neither alphanumeric nor whitespace
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Punctuation {
+    /// Remove them
+    Strip,
+    /// Leave them attached to the surrounding text
+    Keep,
+    /// Separate each one into a token of its own
+    Tokens,
+}
+
+/// Which letters to convert to lower case
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseFold {
+    /// Leave the casing alone
+    Keep,
+    /// Only lower-case ASCII letters, as the original word tokenizer did
+    Ascii,
+    /// Lower-case every letter with a Unicode lower case mapping
+    Full,
+}
+
+/// A text normalisation pipeline, applied before tokenization
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Normaliser {
+    /// Apply Unicode NFKC normalisation, folding compatibility characters such as full-width letters
+    pub nfkc: bool,
+    pub case_fold: CaseFold,
+    pub punctuation: Punctuation,
+    pub urls: bool,
+    pub emails: bool,
+    pub numbers: bool,
+    pub newline_tokens: bool,
+}
+
+impl Default for Normaliser {
+    fn default() -> Normaliser {
+        Normaliser::legacy()
+    }
+}
+
+impl Normaliser {
+    /// Lower-case the text and strip punctuation, matching the original word tokenizer
+    pub fn legacy() -> Normaliser {
+        Normaliser {
+            nfkc: false,
+            case_fold: CaseFold::Ascii,
+            punctuation: Punctuation::Strip,
+            urls: false,
+            emails: false,
+            numbers: false,
+            newline_tokens: false,
+        }
+    }
+
+    /// Keep the casing, punctuation and line breaks which distinguish authors
+    pub fn stylistic() -> Normaliser {
+        Normaliser {
+            nfkc: true,
+            case_fold: CaseFold::Keep,
+            punctuation: Punctuation::Tokens,
+            urls: true,
+            emails: true,
+            numbers: true,
+            newline_tokens: true,
+        }
+    }
+
+    /// Normalise a message. Placeholders are always separated from the text around them by spaces.
+    pub fn normalise(&self, msg: &str) -> String {
+        let text: String = if self.nfkc { msg.nfkc().collect() } else { msg.to_string() };
+        let text = match self.case_fold {
+            CaseFold::Keep => text,
+            CaseFold::Ascii => text.to_ascii_lowercase(),
+            CaseFold::Full => text.to_lowercase(),
+        };
+
+        let mut output = String::with_capacity(text.len());
+        let mut chunk = String::new();
+
+        // Normalise each whitespace-separated chunk, keeping the whitespace between them
+        for c in text.chars() {
+            if !c.is_whitespace() {
+                chunk.push(c);
+                continue;
+            }
+            output.push_str(&self.normalise_chunk(&std::mem::take(&mut chunk)));
+            if c == '\n' && self.newline_tokens {
+                output.push_str(&format!(" {} ", NEWLINE_TOKEN));
+            } else {
+                output.push(c);
+            }
+        }
+        output.push_str(&self.normalise_chunk(&chunk));
+
+        output
+    }
+
+    /// Normalise a chunk of text containing no whitespace
+    fn normalise_chunk(&self, chunk: &str) -> String {
+        // Look for placeholders inside any surrounding punctuation, as in "(https://example.com)."
+        let core = chunk.trim_matches(|c: char| !c.is_alphanumeric());
+        if let Some(placeholder) = self.placeholder(core) {
+            let start = chunk.find(core).unwrap();
+            let prefix = self.normalise_punctuation(&chunk[..start]);
+            let suffix = self.normalise_punctuation(&chunk[start + core.len()..]);
+            return format!("{} {} {}", prefix, placeholder, suffix);
+        }
+
+        self.normalise_punctuation(chunk)
+    }
+
+    /// The placeholder which replaces the text, if any
+    fn placeholder(&self, text: &str) -> Option<&'static str> {
+        if text.is_empty() {
+            return None;
+        }
+
+        if self.urls && (text.starts_with("http://") || text.starts_with("https://") || text.starts_with("www.")) {
+            return Some(URL_TOKEN);
+        }
+
+        if self.emails {
+            if let Some((local, domain)) = text.split_once('@') {
+                if !local.is_empty() && domain.contains('.') && !domain.starts_with('.') {
+                    return Some(EMAIL_TOKEN);
+                }
+            }
+        }
+
+        let is_number = text.chars().all(|c| c.is_ascii_digit() || c == '.' || c == ',');
+        if self.numbers && is_number {
+            return Some(NUMBER_TOKEN);
+        }
+
+        None
+    }
+
+    /// Apply the punctuation setting to every non-alphanumeric character
+    fn normalise_punctuation(&self, text: &str) -> String {
+        let mut output = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                output.push(c);
+                continue;
+            }
+            match self.punctuation {
+                Punctuation::Strip => {}
+                Punctuation::Keep => output.push(c),
+                Punctuation::Tokens => {
+                    output.push(' ');
+                    output.push(c);
+                    output.push(' ');
+                }
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The word tokenizer's cleaning before the normaliser replaced it
+    fn clean_msg(msg: String) -> String {
+        let mut clean_msg: String = String::new();
+
+        let chars: String = msg
+            .to_ascii_lowercase()
+            .chars()
+            .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+            .collect::<String>();
+
+        let words = chars
+            .split_whitespace()
+            .collect::<Vec<&str>>();
+
+        for word in words {
+            clean_msg.push_str(word);
+            clean_msg.push(' ');
+        }
+
+        clean_msg.trim().to_string()
+    }
+
+    fn words(normaliser: &Normaliser, msg: &str) -> Vec<String> {
+        normaliser.normalise(msg).split_whitespace().map(str::to_string).collect()
+    }
+
+    fn with_punctuation(punctuation: Punctuation) -> Normaliser {
+        Normaliser { punctuation, ..Normaliser::legacy() }
+    }
+
+    #[test]
+    fn legacy_matches_clean_msg() {
+        let messages = [
+            "I love this chat! It's so good.",
+            "  Tabs\tand\nnew\r\nlines  ",
+            "ÄÖÜ Straße İstanbul ΣΑΣ",
+            "ｆｕｌｌ ｗｉｄｔｈ ﬁne",
+            "see https://example.com/a?b=1 or me@example.com",
+            "12,000.5 (42) 4th",
+            "emoji 😀 only... ?!",
+            "",
+        ];
+        for msg in messages {
+            let normalised = Normaliser::legacy().normalise(msg).split_whitespace().collect::<Vec<_>>().join(" ");
+            assert_eq!(normalised, clean_msg(msg.to_string()), "{:?}", msg);
+        }
+    }
+
+    #[test]
+    fn nfkc_folds_compatibility_characters() {
+        let nfkc = Normaliser { nfkc: true, ..Normaliser::legacy() };
+        assert_eq!(nfkc.normalise("ｆｕｌｌ ﬁne"), "full fine");
+        assert_eq!(Normaliser::legacy().normalise("ｆｕｌｌ ﬁne"), "ｆｕｌｌ ﬁne");
+    }
+
+    #[test]
+    fn case_folding() {
+        let normalise = |case_fold| Normaliser { case_fold, ..Normaliser::legacy() }.normalise("HeLLo ÄÖ");
+        assert_eq!(normalise(CaseFold::Keep), "HeLLo ÄÖ");
+        assert_eq!(normalise(CaseFold::Ascii), "hello ÄÖ");
+        assert_eq!(normalise(CaseFold::Full), "hello äö");
+    }
+
+    #[test]
+    fn punctuation_modes() {
+        let msg = "it's ok!";
+        assert_eq!(with_punctuation(Punctuation::Strip).normalise(msg), "its ok");
+        assert_eq!(with_punctuation(Punctuation::Keep).normalise(msg), "it's ok!");
+        assert_eq!(with_punctuation(Punctuation::Tokens).normalise(msg), "it ' s ok ! ");
+    }
+
+    #[test]
+    fn url_placeholders() {
+        let stylistic = Normaliser::stylistic();
+        assert_eq!(words(&stylistic, "see https://example.com/a?b=1 now"), ["see", "<url>", "now"]);
+        assert_eq!(words(&stylistic, "http://a.b www.example.com"), ["<url>", "<url>"]);
+        assert_eq!(words(&stylistic, "(https://example.com)."), ["(", "<url>", ")", "."]);
+        assert_eq!(words(&stylistic, "\"www.example.com\","), ["\"", "<url>", "\"", ","]);
+
+        let stripped = Normaliser { punctuation: Punctuation::Strip, ..Normaliser::stylistic() };
+        assert_eq!(words(&stripped, "(https://example.com)."), ["<url>"]);
+    }
+
+    #[test]
+    fn email_placeholders() {
+        let stylistic = Normaliser::stylistic();
+        assert_eq!(words(&stylistic, "mail me@example.com"), ["mail", "<email>"]);
+        assert_eq!(words(&stylistic, "<me@example.com>"), ["<", "<email>", ">"]);
+        assert_eq!(words(&stylistic, "a@b"), ["a", "@", "b"]);
+        assert_eq!(words(&stylistic, "@example.com"), ["@", "example", ".", "com"]);
+    }
+
+    #[test]
+    fn number_placeholders() {
+        let stylistic = Normaliser::stylistic();
+        assert_eq!(words(&stylistic, "12,000.5 apples"), ["<num>", "apples"]);
+        assert_eq!(words(&stylistic, "(42)!"), ["(", "<num>", ")", "!"]);
+        assert_eq!(words(&stylistic, "4th"), ["4th"]);
+    }
+
+    #[test]
+    fn newline_placeholders() {
+        assert_eq!(words(&Normaliser::stylistic(), "hi\n\nbye"), ["hi", "<nl>", "<nl>", "bye"]);
+        assert_eq!(Normaliser::legacy().normalise("hi\nbye"), "hi\nbye");
+    }
+
+    #[test]
+    fn placeholders_off_in_legacy() {
+        assert_eq!(Normaliser::legacy().normalise("https://x.com me@x.com 42"), "httpsxcom mexcom 42");
+    }
+}
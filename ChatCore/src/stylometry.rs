@@ -0,0 +1,189 @@
+/// Common words whose relative frequencies are characteristic of an author
+pub const FUNCTION_WORDS: [&str; 24] = [
+    "the", "a", "an", "and", "but", "or", "of", "to", "in", "on", "for", "with",
+    "is", "are", "be", "it", "that", "this", "i", "you", "my", "me", "not", "can",
+];
+
+/// The names of the features which don't depend on a word list, in the order they are extracted
+pub const STYLE_FEATURES: [&str; 20] = [
+    "log_chars",
+    "log_words",
+    "log_lines",
+    "mean_word_length",
+    "uppercase_ratio",
+    "digit_ratio",
+    "whitespace_ratio",
+    "punctuation_ratio",
+    "non_ascii_ratio",
+    "exclamation_ratio",
+    "question_ratio",
+    "comma_ratio",
+    "period_ratio",
+    "apostrophe_ratio",
+    "starts_uppercase",
+    "ends_with_punctuation",
+    "markdown_code",
+    "markdown_lists",
+    "markdown_headers",
+    "markdown_emphasis",
+];
+
+/// The number of features extracted from each message
+pub const NUM_FEATURES: usize = STYLE_FEATURES.len() + FUNCTION_WORDS.len();
+
+/// The name of every feature, in the order they are extracted
+pub fn feature_names() -> Vec<String> {
+    let style = STYLE_FEATURES.iter().map(|name| name.to_string());
+    let function_words = FUNCTION_WORDS.iter().map(|word| format!("word_{}", word));
+    style.chain(function_words).collect()
+}
+
+/// The fraction of `total` made up by `count`, or zero for empty messages
+fn ratio(count: usize, total: usize) -> f32 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f32 / total as f32
+    }
+}
+
+/// Extract hand-crafted stylometric features from the raw content of a message.
+/// Counts are log-scaled and everything else is a ratio, so every feature has a similar range.
+pub fn extract_features(text: &str) -> Vec<f32> {
+    let chars: Vec<char> = text.chars().collect();
+    let num_chars = chars.len();
+    let count = |predicate: fn(&char) -> bool| chars.iter().filter(|c| predicate(c)).count();
+    let count_char = |target: char| chars.iter().filter(|&&c| c == target).count();
+
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+    let word_chars: usize = words.iter().map(|word| word.chars().count()).sum();
+    let lines: Vec<&str> = text.lines().collect();
+
+    // Markdown is counted per line, as it is only meaningful at the start of one
+    let trimmed_lines = || lines.iter().map(|line| line.trim_start());
+    let code_blocks = trimmed_lines().filter(|line| line.starts_with("```")).count();
+    let list_items = trimmed_lines().filter(|line| {
+        line.starts_with("- ") || line.starts_with("* ") || line.split_once(". ").is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    }).count();
+    let headers = trimmed_lines().filter(|line| line.starts_with('#')).count();
+    // Backticks which aren't part of a code fence mark inline code
+    let inline_code = text.matches('`').count().saturating_sub(3 * text.matches("```").count());
+    let emphasis = text.matches("**").count() + inline_code;
+
+    let letters = count(|c| c.is_alphabetic());
+    let mut features = vec![
+        (num_chars as f32).ln_1p(),
+        (words.len() as f32).ln_1p(),
+        (lines.len() as f32).ln_1p(),
+        ratio(word_chars, words.len()),
+        ratio(count(|c| c.is_uppercase()), letters),
+        ratio(count(|c| c.is_numeric()), num_chars),
+        ratio(count(|c| c.is_whitespace()), num_chars),
+        ratio(count(|c| !c.is_alphanumeric() && !c.is_whitespace()), num_chars),
+        ratio(count(|c| !c.is_ascii()), num_chars),
+        ratio(count_char('!'), num_chars),
+        ratio(count_char('?'), num_chars),
+        ratio(count_char(','), num_chars),
+        ratio(count_char('.'), num_chars),
+        ratio(count_char('\''), num_chars),
+        chars.first().is_some_and(|c| c.is_uppercase()) as u8 as f32,
+        text.trim_end().chars().last().is_some_and(|c| matches!(c, '.' | '!' | '?')) as u8 as f32,
+        ratio(code_blocks, lines.len()),
+        ratio(list_items, lines.len()),
+        ratio(headers, lines.len()),
+        (emphasis as f32).ln_1p(),
+    ];
+
+    // Function words are measured relative to the length of the message
+    for function_word in FUNCTION_WORDS {
+        features.push(ratio(words.iter().filter(|word| *word == function_word).count(), words.len()));
+    }
+
+    features
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert the named features of a message have the expected values
+    fn assert_features(text: &str, expected: &[(&str, f32)]) {
+        let features = extract_features(text);
+        let names = feature_names();
+        for (name, value) in expected {
+            let index = names.iter().position(|n| n == name).unwrap_or_else(|| panic!("No feature called {}", name));
+            assert!((features[index] - value).abs() < 1e-6, "{} of {:?} is {}, not {}", name, text, features[index], value);
+        }
+    }
+
+    #[test]
+    fn one_value_per_feature() {
+        assert_eq!(feature_names().len(), NUM_FEATURES);
+        for text in ["", "Hi, I can't go!", "# Title\n- one\n```\ncode\n```"] {
+            assert_eq!(extract_features(text).len(), NUM_FEATURES);
+        }
+    }
+
+    #[test]
+    fn empty_message() {
+        let features = extract_features("");
+        assert!(features.iter().all(|&feature| feature == 0.0), "{:?}", features);
+    }
+
+    #[test]
+    fn ratios() {
+        assert_features("Hi, I can't go!", &[
+            ("log_chars", 16.0f32.ln()),
+            ("log_words", 5.0f32.ln()),
+            ("log_lines", 2.0f32.ln()),
+            ("mean_word_length", 2.5),
+            ("uppercase_ratio", 2.0 / 9.0),
+            ("digit_ratio", 0.0),
+            ("whitespace_ratio", 3.0 / 15.0),
+            ("punctuation_ratio", 3.0 / 15.0),
+            ("non_ascii_ratio", 0.0),
+            ("exclamation_ratio", 1.0 / 15.0),
+            ("question_ratio", 0.0),
+            ("comma_ratio", 1.0 / 15.0),
+            ("period_ratio", 0.0),
+            ("apostrophe_ratio", 1.0 / 15.0),
+            ("starts_uppercase", 1.0),
+            ("ends_with_punctuation", 1.0),
+            ("markdown_emphasis", 0.0),
+            ("word_i", 0.25),
+            ("word_can", 0.0),
+        ]);
+
+        assert_features("olá 42?  ", &[
+            ("uppercase_ratio", 0.0),
+            ("digit_ratio", 2.0 / 9.0),
+            ("whitespace_ratio", 3.0 / 9.0),
+            ("non_ascii_ratio", 1.0 / 9.0),
+            ("question_ratio", 1.0 / 9.0),
+            ("starts_uppercase", 0.0),
+            ("ends_with_punctuation", 1.0),
+        ]);
+    }
+
+    #[test]
+    fn markdown_and_code() {
+        let text = "# Title\n- one\n* two\n3. three\n```\ncode\n```\nuse **bold** and `x`";
+        assert_features(text, &[
+            ("log_lines", 9.0f32.ln()),
+            ("markdown_code", 2.0 / 8.0),
+            ("markdown_lists", 3.0 / 8.0),
+            ("markdown_headers", 1.0 / 8.0),
+            // Two ** and two inline backticks, as the fences' backticks are not counted
+            ("markdown_emphasis", 5.0f32.ln()),
+        ]);
+
+        assert_features("v2. no list\n1.5 apples\n  ## indented", &[
+            ("markdown_lists", 0.0),
+            ("markdown_headers", 1.0 / 3.0),
+        ]);
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::normalise::{Normaliser, PLACEHOLDERS};
+
+/// Marks a piece of text which followed a space
+const SPACE_MARKER: char = '▁';
+
+/// How normalised text is split into tokens
+#[derive(Serialize, Deserialize, Clone)]
+pub enum TokenizerModel {
+    /// Words split on whitespace
+    Word,
+    /// Subwords learned by byte-pair encoding, keeping case, punctuation and formatting
+    Bpe(Bpe),
+}
+
+/// Splits chat messages into the tokens used by the embeddings and the transformer
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tokenizer {
+    pub normaliser: Normaliser,
+    pub model: TokenizerModel,
+}
+
+impl Default for Tokenizer {
+    fn default() -> Tokenizer {
+        Tokenizer::word(Normaliser::legacy())
+    }
+}
+
+impl Tokenizer {
+    /// Create a tokenizer which splits normalised text on whitespace
+    pub fn word(normaliser: Normaliser) -> Tokenizer {
+        Tokenizer { normaliser, model: TokenizerModel::Word }
+    }
+
+    /// Create a tokenizer by learning byte-pair encoding merges from the normalised messages
+    pub fn train_bpe(normaliser: Normaliser, messages: &[String], vocab_size: usize) -> Tokenizer {
+        let normalised: Vec<String> = messages.iter().map(|msg| normaliser.normalise(msg)).collect();
+        let bpe = Bpe::train(&normalised, vocab_size);
+        Tokenizer { normaliser, model: TokenizerModel::Bpe(bpe) }
+    }
+
+    /// Normalise a message and split it into tokens
+    pub fn tokenize(&self, msg: &str) -> Vec<String> {
+        let text = self.normaliser.normalise(msg);
+        match &self.model {
+            TokenizerModel::Word => text.split_whitespace().map(str::to_string).collect(),
+            TokenizerModel::Bpe(bpe) => bpe.tokenize(&text),
+        }
+    }
+
+    /// Load a tokenizer from a vocabulary file
+    pub fn load(file_name: &str) -> Tokenizer {
+        let file = File::open(file_name).expect("Failed to open tokenizer file");
+        serde_json::from_reader(std::io::BufReader::new(file)).expect("Failed to read tokenizer file")
+    }
+
+    /// Save the tokenizer to a vocabulary file
+    pub fn save(&self, file_name: &str) {
+        let serialized = serde_json::to_string(self).unwrap();
+        let mut file = File::create(file_name).expect("Unable to create file");
+        file.write_all(serialized.as_bytes()).expect("Unable to write");
+    }
+}
+
+/// Which kind of characters make up a piece of text
+#[derive(PartialEq, Clone, Copy)]
+enum CharClass {
+    Alphanumeric,
+    Space,
+    Newline,
+    Symbol,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_alphanumeric() {
+        CharClass::Alphanumeric
+    } else if c == '\n' {
+        CharClass::Newline
+    } else if c.is_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Symbol
+    }
+}
+
+/// Split a message into runs of alphanumerics, symbols and newlines, and
+/// placeholders from the normaliser. Merges never cross these pieces. Spaces
+/// are dropped, but mark the piece after them.
+fn pre_tokenize(msg: &str) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    let mut class = CharClass::Space;
+    let mut chars = msg.char_indices();
+
+    while let Some((index, c)) = chars.next() {
+        // Placeholders always make up a piece of their own
+        if let Some(placeholder) = PLACEHOLDERS.iter().find(|placeholder| msg[index..].starts_with(**placeholder)) {
+            if !piece.is_empty() {
+                pieces.push(std::mem::take(&mut piece));
+            }
+            if class == CharClass::Space && !pieces.is_empty() {
+                piece.push(SPACE_MARKER);
+            }
+            piece.push_str(placeholder);
+            pieces.push(std::mem::take(&mut piece));
+            // Skip the rest of the placeholder
+            chars.nth(placeholder.chars().count() - 2);
+            class = CharClass::Symbol;
+            continue;
+        }
+
+        let next_class = char_class(c);
+        // Newlines are kept as one piece each so blank lines are preserved
+        if next_class != class || next_class == CharClass::Newline {
+            if !piece.is_empty() {
+                pieces.push(std::mem::take(&mut piece));
+            }
+            if class == CharClass::Space && next_class != CharClass::Space && next_class != CharClass::Newline && !pieces.is_empty() {
+                piece.push(SPACE_MARKER);
+            }
+            class = next_class;
+        }
+        if next_class != CharClass::Space {
+            piece.push(c);
+        }
+    }
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+
+    pieces
+}
+
+/// Split a piece into the symbols which merges are learned from
+fn symbols(piece: &str) -> Vec<String> {
+    if PLACEHOLDERS.contains(&piece.trim_start_matches(SPACE_MARKER)) {
+        return vec![piece.to_string()];
+    }
+    piece.chars().map(String::from).collect()
+}
+
+// Defines a byte-pair encoding vocabulary
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "BpeFile")]
+pub struct Bpe {
+    merges: Vec<(String, String)>,
+    vocab: Vec<String>,
+    #[serde(skip)]
+    ranks: HashMap<(String, String), usize>,
+}
+
+// Defines the serialized fields of a byte-pair encoding vocabulary
+#[derive(Deserialize)]
+struct BpeFile {
+    merges: Vec<(String, String)>,
+    vocab: Vec<String>,
+}
+
+impl From<BpeFile> for Bpe {
+    fn from(file: BpeFile) -> Bpe {
+        Bpe::new(file.merges, file.vocab)
+    }
+}
+
+impl Bpe {
+    /// Learn merges from the messages until the vocabulary reaches `vocab_size` tokens
+    fn train(messages: &[String], vocab_size: usize) -> Bpe {
+        // Count each distinct piece, splitting it into characters
+        let mut piece_counts: HashMap<String, i64> = HashMap::new();
+        for msg in messages {
+            for piece in pre_tokenize(msg) {
+                *piece_counts.entry(piece).or_insert(0) += 1;
+            }
+        }
+        let mut pieces: Vec<(Vec<String>, i64)> = piece_counts
+            .into_iter()
+            .map(|(piece, count)| (symbols(&piece), count))
+            .collect();
+
+        let mut vocab: HashSet<String> = pieces.iter().flat_map(|(symbols, _)| symbols.iter().cloned()).collect();
+
+        // Count every adjacent pair of symbols, and which pieces they appear in
+        let mut pair_counts: HashMap<(String, String), i64> = HashMap::new();
+        let mut pair_pieces: HashMap<(String, String), HashSet<usize>> = HashMap::new();
+        for (index, (symbols, count)) in pieces.iter().enumerate() {
+            for pair in symbols.windows(2) {
+                let pair = (pair[0].clone(), pair[1].clone());
+                *pair_counts.entry(pair.clone()).or_insert(0) += count;
+                pair_pieces.entry(pair).or_default().insert(index);
+            }
+        }
+
+        // Stale heap entries are skipped when their count no longer matches
+        let mut heap: BinaryHeap<(i64, (String, String))> = pair_counts.iter().map(|(pair, &count)| (count, pair.clone())).collect();
+        let mut merges = Vec::new();
+
+        while vocab.len() < vocab_size {
+            let Some((count, pair)) = heap.pop() else { break };
+            if pair_counts.get(&pair) != Some(&count) {
+                continue;
+            }
+            // Pairs which only appear once are not worth merging
+            if count < 2 {
+                break;
+            }
+
+            let merged = format!("{}{}", pair.0, pair.1);
+            let mut changed: HashSet<(String, String)> = HashSet::new();
+
+            for index in pair_pieces.remove(&pair).unwrap_or_default() {
+                let (symbols, piece_count) = &mut pieces[index];
+
+                // Remove the old pairs of this piece
+                for old in symbols.windows(2) {
+                    let old = (old[0].clone(), old[1].clone());
+                    *pair_counts.get_mut(&old).unwrap() -= *piece_count;
+                    changed.insert(old);
+                }
+
+                *symbols = merge_pair(symbols, &pair, &merged);
+
+                // Add the new pairs of this piece
+                for new in symbols.windows(2) {
+                    let new = (new[0].clone(), new[1].clone());
+                    *pair_counts.entry(new.clone()).or_insert(0) += *piece_count;
+                    pair_pieces.entry(new.clone()).or_default().insert(index);
+                    changed.insert(new);
+                }
+            }
+
+            for changed_pair in changed {
+                let changed_count = pair_counts[&changed_pair];
+                if changed_count > 0 {
+                    heap.push((changed_count, changed_pair));
+                }
+            }
+
+            vocab.insert(merged);
+            merges.push(pair);
+        }
+
+        let mut vocab: Vec<String> = vocab.into_iter().collect();
+        vocab.sort();
+
+        Bpe::new(merges, vocab)
+    }
+
+    /// Create a vocabulary from merges in the order they were learned
+    fn new(merges: Vec<(String, String)>, vocab: Vec<String>) -> Bpe {
+        let ranks = merges.iter().cloned().enumerate().map(|(rank, pair)| (pair, rank)).collect();
+        Bpe { merges, vocab, ranks }
+    }
+
+    /// The tokens in the vocabulary
+    pub fn vocab(&self) -> &[String] {
+        &self.vocab
+    }
+
+    /// Split a message into subword tokens by applying the merges in the order they were learned
+    pub fn tokenize(&self, msg: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for piece in pre_tokenize(msg) {
+            let mut symbols: Vec<String> = symbols(&piece);
+
+            // Repeatedly merge the earliest learned pair in the piece
+            loop {
+                let best = symbols
+                    .windows(2)
+                    .filter_map(|pair| self.ranks.get(&(pair[0].clone(), pair[1].clone())).map(|&rank| (rank, pair[0].clone(), pair[1].clone())))
+                    .min();
+                let Some((_, left, right)) = best else { break };
+                let merged = format!("{}{}", left, right);
+                symbols = merge_pair(&symbols, &(left, right), &merged);
+            }
+
+            tokens.extend(symbols);
+        }
+
+        tokens
+    }
+}
+
+/// Replace every occurrence of `pair` in `symbols` with `merged`
+fn merge_pair(symbols: &[String], pair: &(String, String), merged: &str) -> Vec<String> {
+    let mut output = Vec::with_capacity(symbols.len());
+    let mut i = 0;
+    while i < symbols.len() {
+        if i + 1 < symbols.len() && symbols[i] == pair.0 && symbols[i + 1] == pair.1 {
+            output.push(merged.to_string());
+            i += 2;
+        } else {
+            output.push(symbols[i].clone());
+            i += 1;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| token.to_string()).collect()
+    }
+
+    #[test]
+    fn pre_tokenize_marks_spaces() {
+        assert_eq!(pre_tokenize("hi there, you"), strings(&["hi", "▁there", ",", "▁you"]));
+        assert_eq!(pre_tokenize("  hi"), strings(&["hi"]));
+    }
+
+    #[test]
+    fn pre_tokenize_keeps_each_newline() {
+        assert_eq!(pre_tokenize("hi\n\nbye"), strings(&["hi", "\n", "\n", "bye"]));
+        assert_eq!(pre_tokenize("hi \n bye"), strings(&["hi", "\n", "▁bye"]));
+    }
+
+    #[test]
+    fn pre_tokenize_skips_placeholders() {
+        assert_eq!(pre_tokenize("see <url> now"), strings(&["see", "▁<url>", "▁now"]));
+        assert_eq!(pre_tokenize("<url><num>"), strings(&["<url>", "<num>"]));
+        assert_eq!(pre_tokenize("a<email>b"), strings(&["a", "<email>", "b"]));
+        assert_eq!(pre_tokenize("<nl>\nx"), strings(&["<nl>", "\n", "x"]));
+    }
+
+    #[test]
+    fn placeholders_are_one_symbol() {
+        assert_eq!(symbols("▁<url>"), strings(&["▁<url>"]));
+        assert_eq!(symbols("▁ab"), strings(&["▁", "a", "b"]));
+    }
+
+    #[test]
+    fn train_merges_most_frequent_pairs() {
+        let messages = strings(&["ab ab ab"]);
+
+        let bpe = Bpe::train(&messages, 4);
+        assert_eq!(bpe.merges, vec![("a".to_string(), "b".to_string())]);
+        assert_eq!(bpe.vocab(), strings(&["a", "ab", "b", "▁"]).as_slice());
+        assert_eq!(bpe.tokenize("ab ab"), strings(&["ab", "▁", "ab"]));
+
+        let bpe = Bpe::train(&messages, 5);
+        assert_eq!(bpe.tokenize("ab ab"), strings(&["ab", "▁ab"]));
+        assert_eq!(bpe.tokenize("ba"), strings(&["b", "a"]));
+    }
+
+    #[test]
+    fn train_stops_at_single_pairs() {
+        let bpe = Bpe::train(&strings(&["ab ab ab cd"]), 100);
+        assert_eq!(bpe.merges.len(), 2);
+        assert!(!bpe.vocab().contains(&"cd".to_string()));
+    }
+
+    #[test]
+    fn save_and_load_rebuild_ranks() {
+        let tokenizer = Tokenizer::train_bpe(Normaliser::stylistic(), &strings(&["Hello hello there", "hello there!"]), 20);
+        let serialized = serde_json::to_string(&tokenizer).unwrap();
+        let loaded: Tokenizer = serde_json::from_str(&serialized).unwrap();
+
+        let TokenizerModel::Bpe(bpe) = &loaded.model else { panic!("Expected a BPE tokenizer") };
+        assert_eq!(bpe.ranks.len(), bpe.merges.len());
+        assert!(!bpe.ranks.is_empty());
+        assert_eq!(loaded.tokenize("hello there, Hello"), tokenizer.tokenize("hello there, Hello"));
+    }
+}
@@ -0,0 +1,153 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use ndarray::{arr1, Array1, Array2, Axis};
+use ndarray::linalg::general_mat_mul;
+use rand::Rng;
+use rusttransformer::{Float, LR};
+use rusttransformer::add_and_norm::AddAndNorm;
+use rusttransformer::block::Block;
+use rusttransformer::config::TransformerConfig;
+use rusttransformer::dense::Dense;
+use rusttransformer::embedding::Embedding;
+use rusttransformer::encoder_block::EncoderBlock;
+use rusttransformer::multi_headed_attention::MultiHeadedAttention;
+use rusttransformer::self_attention::SelfAttention;
+use rusttransformer::transformer::Transformer;
+use chat_core::tokenizer::Tokenizer;
+use std::collections::HashMap;
+use std::hint::black_box;
+
+/// The (num_words, dimensionality) pairs every block is measured at
+const SIZES: [(usize, usize); 3] = [(5, 32), (10, 64), (20, 64)];
+const VOCAB_SIZE: usize = 1000;
+
+fn config(num_words: usize, dimensionality: usize) -> TransformerConfig {
+    TransformerConfig { num_words, dimensionality, ..TransformerConfig::default() }
+}
+
+fn random_matrix(rows: usize, cols: usize) -> Array2<Float> {
+    let mut rng = rand::thread_rng();
+    Array2::from_shape_fn((rows, cols), |_| rng.gen::<Float>() - 0.5)
+}
+
+fn random_vector(len: usize) -> Array1<Float> {
+    let mut rng = rand::thread_rng();
+    Array1::from_shape_fn(len, |_| rng.gen::<Float>() - 0.5)
+}
+
+/// Measure forward propagation, inference and a forward and backward pass of a block.
+/// Back propagation updates the weights, so every batch of the forward and backward pass
+/// starts from a fresh copy of the block, and each run measures the same weights.
+fn bench_block<B: Block + Clone>(c: &mut Criterion, name: &str, mut block: B, input: B::Input, error: B::Output)
+where
+    B::Input: Clone,
+    B::Output: Clone,
+{
+    let mut group = c.benchmark_group(name);
+    group.sample_size(20);
+    group.bench_function("forward", |b| b.iter(|| block.forward_propagate(black_box(input.clone()))));
+    group.bench_function("infer", |b| b.iter(|| block.infer(black_box(input.clone()))));
+    group.bench_function("forward_backward", |b| b.iter_batched(|| block.clone(), |mut block| {
+        block.forward_propagate(black_box(input.clone()));
+        block.back_propagate(black_box(error.clone()))
+    }, BatchSize::SmallInput));
+    group.finish();
+}
+
+fn blocks(c: &mut Criterion) {
+    for (num_words, dimensionality) in SIZES {
+        let config = config(num_words, dimensionality);
+        let (rows, cols) = (config.sequence_length(), config.dimensionality);
+        let size = format!("{}x{}", num_words, dimensionality);
+
+        let self_attention = SelfAttention::new(rows, cols, config.positional_encoding, 0.0);
+        bench_block(c, &format!("SelfAttention/{}", size), self_attention, random_matrix(rows, cols), random_matrix(rows, cols));
+
+        let multi_headed = MultiHeadedAttention::new(config.num_heads, rows, cols, config.positional_encoding);
+        bench_block(c, &format!("MultiHeadedAttention/{}", size), multi_headed, random_matrix(rows, cols), random_matrix(rows, cols));
+
+        let dense = Dense::new(arr1(&[rows*cols, config.hidden_layer_size, rows*cols]), config.feed_forward_activation);
+        bench_block(c, &format!("Dense/{}", size), dense, random_vector(rows*cols), random_vector(rows*cols));
+
+        let add_and_norm = AddAndNorm::new(rows, cols, config.layer_norm_epsilon);
+        bench_block(c, &format!("AddAndNorm/{}", size), add_and_norm, (random_matrix(rows, cols), random_matrix(rows, cols)), random_matrix(rows, cols));
+
+        let encoder_block = EncoderBlock::new(&config);
+        bench_block(c, &format!("EncoderBlock/{}", size), encoder_block, random_matrix(rows, cols), random_matrix(rows, cols));
+    }
+}
+
+/// Back propagate the error of one layer of a Dense block with element-wise loops, as it was
+/// before the weight update used `general_mat_mul`
+fn loop_layer_backward(weights: &mut Array2<Float>, layer: &Array1<Float>, next_error: &Array1<Float>) -> Array1<Float> {
+    let mut error = Array1::<Float>::zeros(layer.len());
+    for j in 0..layer.len() {
+        for k in 0..next_error.len() {
+            error[j] += weights[[j, k]] * next_error[k];
+            weights[[j, k]] -= layer[j] * next_error[k] * LR;
+        }
+    }
+    error
+}
+
+/// Back propagate the error of one layer of a Dense block the way `Dense::back_propagate` does
+fn vectorised_layer_backward(weights: &mut Array2<Float>, layer: &Array1<Float>, next_error: &Array1<Float>) -> Array1<Float> {
+    let error = weights.dot(next_error);
+    let layer = layer.view().insert_axis(Axis(1));
+    let next_error = next_error.view().insert_axis(Axis(0));
+    general_mat_mul(-LR, &layer, &next_error, 1.0, weights);
+    error
+}
+
+/// Compare the element-wise loops Dense back propagation used to run with the vectorised
+/// version, on the first layer of the feed-forward network at each size
+fn dense_backward(c: &mut Criterion) {
+    let mut group = c.benchmark_group("DenseLayerBackward");
+    group.sample_size(20);
+
+    for (num_words, dimensionality) in SIZES {
+        let config = config(num_words, dimensionality);
+        let inputs = config.sequence_length() * config.dimensionality;
+        let weights = random_matrix(inputs, config.hidden_layer_size);
+        let layer = random_vector(inputs);
+        let next_error = random_vector(config.hidden_layer_size);
+        let size = format!("{}x{}", num_words, dimensionality);
+
+        group.bench_with_input(BenchmarkId::new("loops", &size), &weights, |b, weights| b.iter_batched(|| weights.clone(), |mut weights| {
+            loop_layer_backward(&mut weights, black_box(&layer), black_box(&next_error))
+        }, BatchSize::SmallInput));
+        group.bench_with_input(BenchmarkId::new("general_mat_mul", &size), &weights, |b, weights| b.iter_batched(|| weights.clone(), |mut weights| {
+            vectorised_layer_backward(&mut weights, black_box(&layer), black_box(&next_error))
+        }, BatchSize::SmallInput));
+    }
+    group.finish();
+}
+
+/// Measure the throughput of training and prediction on whole messages, in messages per second
+fn transformer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Transformer");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(1));
+
+    for (num_words, dimensionality) in SIZES {
+        let config = config(num_words, dimensionality);
+        let mut rng = rand::thread_rng();
+        let vectors: HashMap<String, Vec<f32>> = (0..VOCAB_SIZE).map(|i| (format!("w{}", i), (0..dimensionality).map(|_| rng.gen()).collect())).collect();
+        let embedding = Embedding::new(vectors, &config);
+        let transformer = Transformer::new(config, Tokenizer::default(), embedding);
+
+        let msg: Vec<String> = (0..num_words).map(|_| format!("w{}", rng.gen_range(0..VOCAB_SIZE))).collect();
+        let input = transformer.message_input(&msg.join(" "), &[]);
+        let size = format!("{}x{}", num_words, dimensionality);
+
+        group.bench_with_input(BenchmarkId::new("infer", &size), &input, |b, input| b.iter(|| transformer.infer(black_box(input.clone()))));
+        // Each batch trains a fresh copy of the transformer, so the weights don't drift between runs
+        group.bench_with_input(BenchmarkId::new("train_step", &size), &input, |b, input| b.iter_batched(|| transformer.clone(), |mut transformer| {
+            transformer.forward_propagate(black_box(input.clone()));
+            transformer.back_propagate(arr1(&[1.0, 0.0]))
+        }, BatchSize::LargeInput));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, blocks, dense_backward, transformer);
+criterion_main!(benches);
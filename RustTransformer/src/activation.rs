@@ -0,0 +1,98 @@
+use ndarray::Array1;
+use crate::dense::softmax;
+use crate::BlockFloat;
+use serde::{Serialize, Deserialize};
+
+// sqrt(2 / pi), used by the tanh approximation of GELU
+const GELU_SCALE: f64 = 0.797_884_560_802_865_4;
+const GELU_CUBIC: f64 = 0.044715;
+
+/// Activation functions which can be applied to the output of a dense layer
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activation {
+    Identity,
+    Relu,
+    Gelu,
+    Silu,
+    Tanh,
+    Sigmoid,
+    /// Softmax is only used on classifier outputs trained with cross entropy,
+    /// so the error it receives is already with respect to its input
+    Softmax,
+}
+
+fn sigmoid<F: BlockFloat>(x: F) -> F {
+    F::one() / (F::one() + (-x).exp())
+}
+
+impl Activation {
+    /// Apply the activation function to a weighted sum
+    pub fn apply<F: BlockFloat>(&self, x: Array1<F>) -> Array1<F> {
+        let (half, scale, cubic) = (F::constant(0.5), F::constant(GELU_SCALE), F::constant(GELU_CUBIC));
+        match self {
+            Activation::Identity => x,
+            Activation::Relu => x.mapv(|x| if x > F::zero() { x } else { F::zero() }),
+            Activation::Gelu => x.mapv(|x| half * x * (F::one() + (scale * (x + cubic * x.powi(3))).tanh())),
+            Activation::Silu => x.mapv(|x| x * sigmoid(x)),
+            Activation::Tanh => x.mapv(F::tanh),
+            Activation::Sigmoid => x.mapv(sigmoid),
+            Activation::Softmax => softmax(x),
+        }
+    }
+
+    /// Calculate the derivative of the activation from its weighted sum and output
+    pub fn derivative<F: BlockFloat>(&self, weighted_sum: &Array1<F>, output: &Array1<F>) -> Array1<F> {
+        let (half, scale, cubic, three) = (F::constant(0.5), F::constant(GELU_SCALE), F::constant(GELU_CUBIC), F::constant(3.0));
+        match self {
+            Activation::Identity | Activation::Softmax => Array1::<F>::ones(output.len()),
+            Activation::Relu => weighted_sum.mapv(|x| if x > F::zero() { F::one() } else { F::zero() }),
+            Activation::Gelu => weighted_sum.mapv(|x| {
+                let t = (scale * (x + cubic * x.powi(3))).tanh();
+                half * (F::one() + t) + half * x * (F::one() - t * t) * scale * (F::one() + three * cubic * x * x)
+            }),
+            Activation::Silu => weighted_sum.mapv(|x| {
+                let s = sigmoid(x);
+                s * (F::one() + x * (F::one() - s))
+            }),
+            Activation::Tanh => output.mapv(|y| F::one() - y * y),
+            Activation::Sigmoid => output.mapv(|y| y * (F::one() - y)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{arr1, Array1};
+    use super::Activation;
+    use crate::Float;
+
+    /// Points away from zero, where the derivative of Relu is undefined
+    const POINTS: [Float; 8] = [-3.0, -1.7, -0.6, -0.1, 0.2, 0.9, 1.6, 2.8];
+    const STEP: Float = 1e-2;
+
+    fn check_derivative(activation: Activation) {
+        let x = Array1::from_vec(POINTS.to_vec());
+        let derivative = activation.derivative(&x, &activation.apply(x.clone()));
+        for (i, &point) in POINTS.iter().enumerate() {
+            let plus = activation.apply(arr1(&[point + STEP]))[0];
+            let minus = activation.apply(arr1(&[point - STEP]))[0];
+            let numeric = (plus - minus) / (2.0 * STEP);
+            assert!((derivative[i] - numeric).abs() <= 1e-3, "{:?} derivative at {} is {}, but finite differences give {}", activation, point, derivative[i], numeric);
+        }
+    }
+
+    #[test]
+    fn derivatives_match_finite_differences() {
+        for activation in [Activation::Identity, Activation::Relu, Activation::Gelu, Activation::Silu, Activation::Tanh, Activation::Sigmoid] {
+            check_derivative(activation);
+        }
+    }
+
+    #[test]
+    fn softmax_passes_the_error_through() {
+        let x = arr1(&[0.5 as Float, -1.0, 2.0]);
+        let output = Activation::Softmax.apply(x.clone());
+        assert!((output.sum() - 1.0).abs() < 1e-6);
+        assert_eq!(Activation::Softmax.derivative(&x, &output), Array1::<Float>::ones(3));
+    }
+}
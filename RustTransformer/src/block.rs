@@ -6,6 +6,92 @@ pub trait Block {
     /// Forward propagates input through the block
     fn forward_propagate(&mut self, value: Self::Input) -> Self::Output;
 
+    /// Forward propagates input through the block in evaluation mode, without
+    /// storing anything for back propagation, so one block can serve many threads
+    fn infer(&self, value: Self::Input) -> Self::Output;
+
     /// Back propagates error through the block
     fn back_propagate(&mut self, error: Self::Output) -> Self::Input;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use ndarray::{Array, Dimension};
+    use rand::Rng;
+    use crate::block::Block;
+    use crate::parameters::Parameters;
+    use crate::{BlockFloat, Float, LR};
+
+    /// The step of the central differences. Larger steps are inaccurate where layer norms of
+    /// rows with little variance curve sharply, and smaller steps amplify the rounding error of an f32.
+    /// Floats precise enough for a smaller step use the cube root of their epsilon instead.
+    pub const STEP: Float = 1e-3;
+
+    /// The loss is the sum of the outputs weighted by `weights`, so its gradient with respect to the output is `weights`
+    fn loss<F, B, I, O>(block: &B, input: &Array<F, I>, weights: &Array<F, O>) -> F
+    where
+        F: BlockFloat,
+        B: Block<Input = Array<F, I>, Output = Array<F, O>> + Clone,
+        I: Dimension,
+        O: Dimension,
+    {
+        (block.clone().forward_propagate(input.clone()) * weights).sum()
+    }
+
+    /// Assert the largest difference between two gradients is small next to the largest gradient,
+    /// allowing for an absolute rounding error of `noise`
+    pub fn assert_close<F: BlockFloat>(name: &str, analytic: &[F], numeric: &[F], tolerance: F, noise: F) {
+        let scale = numeric.iter().fold(F::constant(1e-3), |a, &b| a.max(b.abs()));
+        for (i, (a, n)) in analytic.iter().zip(numeric.iter()).enumerate() {
+            assert!((*a - *n).abs() <= tolerance * scale + noise, "{} gradient {} is {}, but finite differences give {}", name, i, a, n);
+        }
+    }
+
+    /// Check the error `back_propagate` returns and the updates it makes to every parameter
+    /// against central differences of a random weighted sum of the outputs
+    pub fn check_gradients<F, B, I, O>(block: &B, input: &Array<F, I>, tolerance: F)
+    where
+        F: BlockFloat,
+        B: Block<Input = Array<F, I>, Output = Array<F, O>> + Parameters<F> + Clone,
+        I: Dimension,
+        O: Dimension,
+    {
+        let mut rng = rand::thread_rng();
+        let output = block.clone().forward_propagate(input.clone());
+        let weights = output.mapv(|_| F::constant(rng.gen::<f64>() - 0.5));
+        let (step, lr, two) = (F::constant(STEP).min(F::epsilon().cbrt()), F::constant(LR), F::constant(2.0));
+
+        // The rounding error of the loss is divided by the step, and the rounding error of each
+        // parameter is divided by the learning rate, which matters for f32 and vanishes for f64
+        let loss_noise = F::constant(10.0) * F::epsilon() * (&output * &weights).mapv(F::abs).sum() / step;
+
+        // Back propagation updates each parameter by -LR times its gradient
+        let mut trained = block.clone();
+        trained.forward_propagate(input.clone());
+        let input_error = trained.back_propagate(weights.clone());
+        let before: Vec<Vec<F>> = block.clone().parameters().iter().map(|p| p.iter().copied().collect()).collect();
+        let after: Vec<Vec<F>> = trained.parameters().iter().map(|p| p.iter().copied().collect()).collect();
+
+        let numeric_input: Vec<F> = (0..input.len()).map(|k| {
+            let mut plus = input.clone();
+            let mut minus = input.clone();
+            *plus.iter_mut().nth(k).unwrap() += step;
+            *minus.iter_mut().nth(k).unwrap() -= step;
+            (loss(block, &plus, &weights) - loss(block, &minus, &weights)) / (two * step)
+        }).collect();
+        assert_close("input", &input_error.iter().copied().collect::<Vec<F>>(), &numeric_input, tolerance, loss_noise);
+
+        for (p, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+            let analytic: Vec<F> = before.iter().zip(after.iter()).map(|(&b, &a)| (b - a) / lr).collect();
+            let numeric: Vec<F> = (0..before.len()).map(|k| {
+                let mut plus = block.clone();
+                let mut minus = block.clone();
+                *plus.parameters()[p].iter_mut().nth(k).unwrap() += step;
+                *minus.parameters()[p].iter_mut().nth(k).unwrap() -= step;
+                (loss(&plus, input, &weights) - loss(&minus, input, &weights)) / (two * step)
+            }).collect();
+            let update_noise = two * F::epsilon() * before.iter().fold(F::zero(), |a, &b| a.max(b.abs())) / lr;
+            assert_close(&format!("parameter {}", p), &analytic, &numeric, tolerance, loss_noise + update_noise);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use ndarray::{s, Array1, Array2, ArrayViewMutD, Axis};
+use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use crate::LR;
+use rand_distr::{Distribution, Normal};
+use serde::{Serialize, Deserialize};
+
+/// The number of character ids. ASCII characters keep their own id, and
+/// other characters share the remaining ids.
+const NUM_CHARS: usize = 256;
+/// Characters after the end of the message are padded with this id
+const PAD_CHAR: usize = 0;
+/// The number of ASCII characters, which take the ids after the padding id
+const NUM_ASCII: usize = 128;
+
+/// Hyperparameters of the character-level encoder
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CharEncoderConfig {
+    /// Messages are truncated or padded to this many characters
+    pub max_chars: usize,
+    pub char_dimensionality: usize,
+    pub num_filters: usize,
+    /// The number of characters each convolution filter reads at once
+    pub kernel_size: usize,
+}
+
+impl Default for CharEncoderConfig {
+    fn default() -> CharEncoderConfig {
+        CharEncoderConfig {
+            max_chars: 256,
+            char_dimensionality: 16,
+            num_filters: 32,
+            kernel_size: 5,
+        }
+    }
+}
+
+/// Map a character to its id, keeping 0 for padding. ASCII characters take ids 1 to 128,
+/// and other characters are hashed into the ids after them so they never share an ASCII id.
+fn char_id(c: char) -> usize {
+    let code = c as usize;
+    if code < NUM_ASCII {
+        code + 1
+    } else {
+        NUM_ASCII + 1 + code % (NUM_CHARS - NUM_ASCII - 1)
+    }
+}
+
+// Defines struct for storing character encoder parameters
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CharEncoderParams<F = Float> {
+    embeddings: Array2::<F>,
+    weights: Array2::<F>,
+    biases: Array1::<F>,
+}
+
+// Defines a character-level convolutional encoder struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CharEncoder<F = Float> {
+    input: String,
+    ids: Array1::<usize>,
+    windows: Array2::<F>,
+    max_positions: Array1::<usize>,
+    output: Array1::<F>,
+    config: CharEncoderConfig,
+    params: CharEncoderParams<F>,
+}
+
+impl<F: FloatStorage> CharEncoder<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> CharEncoder<G> {
+        CharEncoder {
+            input: self.input.clone(),
+            ids: self.ids.clone(),
+            windows: self.windows.mapv(cast),
+            max_positions: self.max_positions.clone(),
+            output: self.output.mapv(cast),
+            config: self.config.clone(),
+            params: CharEncoderParams {
+                embeddings: self.params.embeddings.mapv(cast),
+                weights: self.params.weights.mapv(cast),
+                biases: self.params.biases.mapv(cast),
+            },
+        }
+    }
+}
+
+impl<F: BlockFloat> CharEncoder<F> {
+    /// Create a new character encoder block with the given parameters
+    pub fn new(config: &CharEncoderConfig) -> CharEncoder<F> {
+        assert!(config.kernel_size <= config.max_chars, "The kernel must fit inside a message");
+
+        let window_size = config.kernel_size * config.char_dimensionality;
+        let num_windows = config.max_chars - config.kernel_size + 1;
+
+        // The padding character is learned too, so the filters can see where short messages end
+        let normal = Normal::new(0.0, (1.0 / config.char_dimensionality as f64).sqrt()).unwrap();
+        let embeddings = Array2::<F>::from_shape_fn((NUM_CHARS, config.char_dimensionality), |_| F::constant(normal.sample(&mut rand::thread_rng())));
+
+        // Use He initialisation for the convolution filters
+        let normal = Normal::new(0.0, (2.0 / window_size as f64).sqrt()).unwrap();
+        let weights = Array2::<F>::from_shape_fn((window_size, config.num_filters), |_| F::constant(normal.sample(&mut rand::thread_rng())));
+        let biases = Array1::<F>::zeros(config.num_filters);
+
+        let params = CharEncoderParams { embeddings, weights, biases };
+
+        let block: CharEncoder<F> = CharEncoder {
+            input: String::new(),
+            ids: Array1::<usize>::zeros(config.max_chars),
+            windows: Array2::<F>::zeros((num_windows, window_size)),
+            max_positions: Array1::<usize>::zeros(config.num_filters),
+            output: Array1::<F>::zeros(config.num_filters),
+            config: config.clone(),
+            params,
+        };
+
+        block
+    }
+
+    /// The number of features produced for each message
+    pub fn output_size(&self) -> usize {
+        self.config.num_filters
+    }
+
+    /// Convolve every filter over the message and apply ReLU, returning the id of each
+    /// character and the embeddings of every window of characters too
+    fn convolve(&self, text: &str) -> (Array1<usize>, Array2<F>, Array2<F>) {
+        // Look up the id of each character, padding short messages
+        let mut ids = Array1::<usize>::from_elem(self.config.max_chars, PAD_CHAR);
+        for (i, c) in text.chars().take(self.config.max_chars).enumerate() {
+            ids[i] = char_id(c);
+        }
+
+        // Lay out the embeddings of every window of characters as a row
+        let dimensionality = self.config.char_dimensionality;
+        let mut windows = Array2::<F>::zeros(self.windows.raw_dim());
+        for i in 0..windows.nrows() {
+            for k in 0..self.config.kernel_size {
+                let embedding = self.params.embeddings.row(ids[i + k]);
+                windows.slice_mut(s![i, k * dimensionality..(k + 1) * dimensionality]).assign(&embedding);
+            }
+        }
+
+        let convolved = (windows.dot(&self.params.weights) + &self.params.biases).mapv(|x| x.max(F::zero()));
+
+        (ids, windows, convolved)
+    }
+}
+
+impl<F: BlockFloat> Block for CharEncoder<F> {
+    type Input = String;
+    type Output = Array1<F>;
+
+    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        let (ids, windows, convolved) = self.convolve(&value);
+        self.input = value;
+        self.ids = ids;
+        self.windows = windows;
+
+        // Max-pool each filter over the message, remembering where the maximum was
+        for f in 0..self.config.num_filters {
+            let column = convolved.column(f);
+            let mut max_position = 0;
+            for i in 1..column.len() {
+                if column[i] > column[max_position] {
+                    max_position = i;
+                }
+            }
+            self.max_positions[f] = max_position;
+            self.output[f] = column[max_position];
+        }
+
+        self.output.clone()
+    }
+
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        // Max-pool each filter over the message
+        let (_, _, convolved) = self.convolve(&value);
+        convolved.fold_axis(Axis(0), F::zero(), |&a, &b| a.max(b))
+    }
+
+    /// Characters have no error, so the input is returned unchanged.
+    fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        let dimensionality = self.config.char_dimensionality;
+        let lr = F::constant(LR);
+        let mut window_errors = Array2::<F>::zeros(self.windows.raw_dim());
+
+        for f in 0..self.config.num_filters {
+            // Only the maximum position of an active filter received any error
+            if self.output[f] <= F::zero() {
+                continue;
+            }
+            let position = self.max_positions[f];
+            window_errors.row_mut(position).scaled_add(error[f], &self.params.weights.column(f));
+            self.params.weights.column_mut(f).scaled_add(-lr * error[f], &self.windows.row(position));
+            self.params.biases[f] -= lr * error[f];
+        }
+
+        // Update the embedding of each character in the windows which received error
+        for i in 0..window_errors.nrows() {
+            for k in 0..self.config.kernel_size {
+                let char_error = window_errors.slice(s![i, k * dimensionality..(k + 1) * dimensionality]);
+                self.params.embeddings.row_mut(self.ids[i + k]).scaled_add(-lr, &char_error);
+            }
+        }
+
+        self.input.clone()
+    }
+}
+
+impl<F: BlockFloat> Parameters<F> for CharEncoder<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        vec![
+            self.params.embeddings.view_mut().into_dyn(),
+            self.params.weights.view_mut().into_dyn(),
+            self.params.biases.view_mut().into_dyn(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::tests::{assert_close, STEP};
+
+    /// An encoder with fixed parameters, so no filter's maximum is close to changing position
+    fn encoder() -> CharEncoder {
+        let config = CharEncoderConfig { max_chars: 12, char_dimensionality: 3, num_filters: 4, kernel_size: 3 };
+        let mut encoder = CharEncoder::new(&config);
+        encoder.params.embeddings = Array2::from_shape_fn(encoder.params.embeddings.raw_dim(), |(i, j)| ((i * 7 + j * 13) as Float).sin());
+        encoder.params.weights = Array2::from_shape_fn(encoder.params.weights.raw_dim(), |(i, j)| ((i * 3 + j * 7) as Float).cos() * 0.5);
+        encoder.params.biases = Array1::from_shape_fn(config.num_filters, |f| 0.1 * f as Float);
+        encoder
+    }
+
+    #[test]
+    fn ascii_ids_are_unique() {
+        let ids: Vec<usize> = (0..128u8).map(|c| char_id(c as char)).collect();
+        assert_eq!(ids, (1..=NUM_ASCII).collect::<Vec<usize>>());
+
+        for c in ['š', 'é', 'ß', '😀', '\u{80}', '\u{ff}', '\u{10ffff}'] {
+            let id = char_id(c);
+            assert!(id > NUM_ASCII && id < NUM_CHARS, "{:?} has id {}", c, id);
+        }
+        assert_ne!(char_id('š'), char_id('b'));
+    }
+
+    #[test]
+    fn infer_matches_forward_propagate() {
+        let mut encoder = encoder();
+        for text in ["hello there", "hi", "a much longer message than fits", ""] {
+            assert_eq!(encoder.infer(text.to_string()), encoder.forward_propagate(text.to_string()));
+        }
+    }
+
+    #[test]
+    fn max_pool_routes_error_to_the_maximum() {
+        let mut encoder = encoder();
+        let text = "hello world!".to_string();
+        let output = encoder.forward_propagate(text.clone());
+        let (ids, _, convolved) = encoder.convolve(&text);
+
+        for f in 0..encoder.config.num_filters {
+            let position = encoder.max_positions[f];
+            assert!(convolved.column(f).iter().all(|&x| x <= convolved[[position, f]]));
+            assert_eq!(output[f], convolved[[position, f]]);
+        }
+
+        // Error on one filter only changes that filter and the characters in its maximum window
+        let f = (0..encoder.config.num_filters).find(|&f| output[f] > 0.0).expect("No filter is active");
+        let position = encoder.max_positions[f];
+        let before = encoder.params.clone();
+        let mut error = Array1::<Float>::zeros(encoder.config.num_filters);
+        error[f] = 1.0;
+        encoder.back_propagate(error);
+
+        for g in 0..encoder.config.num_filters {
+            assert_eq!(encoder.params.weights.column(g) != before.weights.column(g), g == f);
+            assert_eq!(encoder.params.biases[g] != before.biases[g], g == f);
+        }
+        let window: Vec<usize> = ids.slice(s![position..position + encoder.config.kernel_size]).to_vec();
+        for id in 0..NUM_CHARS {
+            let changed = encoder.params.embeddings.row(id) != before.embeddings.row(id);
+            assert_eq!(changed, window.contains(&id), "character id {}", id);
+        }
+    }
+
+    #[test]
+    fn gradients() {
+        let encoder = encoder();
+        let text = "hello world!".to_string();
+        let weights = Array1::from_shape_fn(encoder.config.num_filters, |f| (f as Float + 1.0).sin());
+        let loss = |encoder: &CharEncoder| (encoder.infer(text.clone()) * &weights).sum();
+
+        // Max-pooling is only differentiable while the steps can't change which window is the maximum
+        let (_, _, convolved) = encoder.convolve(&text);
+        for column in convolved.columns() {
+            let mut values = column.to_vec();
+            values.sort_by(|a, b| b.partial_cmp(a).unwrap());
+            assert!(values[0] - values[1] > 0.05, "The top two windows are too close to check");
+        }
+
+        let mut trained = encoder.clone();
+        trained.forward_propagate(text.clone());
+        trained.back_propagate(weights.clone());
+
+        let loss_noise = 10.0 * Float::EPSILON * (encoder.infer(text.clone()) * &weights).mapv(Float::abs).sum() / STEP;
+        let before: Vec<Vec<Float>> = encoder.clone().parameters().iter().map(|p| p.iter().copied().collect()).collect();
+        let after: Vec<Vec<Float>> = trained.parameters().iter().map(|p| p.iter().copied().collect()).collect();
+        for (p, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+            let analytic: Vec<Float> = before.iter().zip(after.iter()).map(|(b, a)| (b - a) / LR).collect();
+            let numeric: Vec<Float> = (0..before.len()).map(|k| {
+                let mut plus = encoder.clone();
+                let mut minus = encoder.clone();
+                *plus.parameters()[p].iter_mut().nth(k).unwrap() += STEP;
+                *minus.parameters()[p].iter_mut().nth(k).unwrap() -= STEP;
+                (loss(&plus) - loss(&minus)) / (2.0 * STEP)
+            }).collect();
+            let update_noise = 2.0 * Float::EPSILON * before.iter().fold(0.0, |a: Float, &b| a.max(b.abs())) / LR;
+            assert_close(&format!("parameter {}", p), &analytic, &numeric, 1e-2, loss_noise + update_noise);
+        }
+    }
+}
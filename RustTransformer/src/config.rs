@@ -0,0 +1,70 @@
+use crate::activation::Activation;
+use crate::char_encoder::CharEncoderConfig;
+use crate::embedding::{EmbeddingMode, UnknownVector};
+use crate::positional_encoder::PositionalEncoding;
+use crate::Float;
+use serde::{Serialize, Deserialize};
+
+/// Where layer normalisation sits relative to each residual connection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormPlacement {
+    /// Normalise after adding the residual, as in the original transformer
+    Post,
+    /// Normalise the input of each sub-layer and add the residual unnormalised
+    Pre,
+}
+
+/// Hyperparameters used to build a transformer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransformerConfig {
+    pub num_words: usize,
+    pub dimensionality: usize,
+    pub num_encoders: usize,
+    pub num_heads: usize,
+    pub hidden_layer_size: usize,
+    pub layer_norm_epsilon: Float,
+    pub norm_placement: NormPlacement,
+    pub dropout_rate: Float,
+    pub feed_forward_activation: Activation,
+    pub positional_encoding: PositionalEncoding,
+    pub embedding_mode: EmbeddingMode,
+    pub unknown_vector: UnknownVector,
+    /// Compose vectors for out-of-vocabulary words from their character n-grams
+    pub ngram_fallback: bool,
+    /// Add a character-level encoder whose features are passed to the classifier
+    pub char_encoder: Option<CharEncoderConfig>,
+    /// Pass the stylometric features of each message to the classifier
+    pub stylometric_features: bool,
+    /// The number of previous turns of the conversation encoded before each message
+    pub context_turns: usize,
+}
+
+impl Default for TransformerConfig {
+    fn default() -> TransformerConfig {
+        TransformerConfig {
+            num_words: 10,
+            dimensionality: 64,
+            num_encoders: 1,
+            num_heads: 1,
+            hidden_layer_size: 100,
+            layer_norm_epsilon: 1e-5,
+            norm_placement: NormPlacement::Post,
+            dropout_rate: 0.1,
+            feed_forward_activation: Activation::Relu,
+            positional_encoding: PositionalEncoding::Sinusoidal,
+            embedding_mode: EmbeddingMode::Frozen,
+            unknown_vector: UnknownVector::Learned,
+            ngram_fallback: false,
+            char_encoder: None,
+            stylometric_features: false,
+            context_turns: 0,
+        }
+    }
+}
+
+impl TransformerConfig {
+    /// The number of words the encoders see: each previous turn followed by a separator, then the message
+    pub fn sequence_length(&self) -> usize {
+        self.context_turns * (self.num_words + 1) + self.num_words
+    }
+}
@@ -1,50 +1,50 @@
 use ndarray::Array1;
-use std::collections::HashMap;
+use crate::embedding::{Embedding, PAD_TOKEN};
+use crate::Float;
+use chat_core::dataset::load_chat_records;
+use chat_core::stylometry::extract_features;
+use chat_core::tokenizer::Tokenizer;
+use log::info;
+use serde::{Serialize, Deserialize};
 
-pub struct Message {
-    pub msg: Array1<String>,
-    pub author: usize,
-}
+/// The name of each author, indexed by the class the transformer predicts
+pub const AUTHOR_NAMES: [&str; 2] = ["user", "assistant"];
 
-/// Clean the chat message by removing all non-alphanumeric characters
-/// and un-encoded words
-fn clean_msg(msg: String, word_embeddings: HashMap<String, Vec<f32>>) -> String {
-    // "I love this chat! It's so good."
-    // => "i love this chat its so good "
-    let mut clean_review: String = String::new();
-    
-    let chars: String = msg
-        .to_ascii_lowercase()
-        .chars()
-        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
-        .collect::<String>();
-    
-    let words = chars
-        .split_whitespace()
-        .collect::<Vec<&str>>();
+/// Everything the transformer reads from a single message
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MessageInput {
+    /// The tokens of the message, padded to a fixed length
+    pub words: Array1<String>,
+    /// The raw content of the message
+    pub text: String,
+    /// Stylometric features extracted from the raw content
+    pub features: Array1<Float>,
+    /// The padded tokens of the previous turns of the conversation, oldest first
+    pub context: Vec<Array1<String>>,
+}
 
-    for word in words {
-        if word_embeddings.contains_key(word) {
-            clean_review.push_str(word);
-            clean_review.push(' ');
-        }
+impl MessageInput {
+    /// Create the input for a message, extracting its stylometric features
+    pub fn new(words: Array1<String>, text: String, context: Vec<Array1<String>>) -> MessageInput {
+        let features = Array1::from_vec(extract_features(&text)).mapv(|x| x as Float);
+        MessageInput { words, text, features, context }
     }
+}
 
-    clean_review.trim().to_string()
-
-    
+pub struct Message {
+    pub input: MessageInput,
+    pub author: usize,
 }
 
-/// Pads the msg with empty strings to the desired length
-fn pad_msg(msg: String, msg_size: usize) -> Array1<String> {
-    let words: Vec<&str> = msg.split_whitespace().collect();
+/// Pads the msg with padding tokens to the desired length
+pub(crate) fn pad_msg(words: Vec<String>, msg_size: usize) -> Array1<String> {
     let mut padded_msg = Vec::with_capacity(msg_size);
 
     for i in 0..msg_size {
         let word = if i < words.len() {
-            words[i].to_string()
+            words[i].clone()
         } else {
-            "".to_string()
+            PAD_TOKEN.to_string()
         };
 
         padded_msg.push(word);
@@ -53,29 +53,47 @@ fn pad_msg(msg: String, msg_size: usize) -> Array1<String> {
     Array1::<String>::from_vec(padded_msg)
 }
 
-pub fn load_chat_dataset(json_path: &str, msg_size: usize, word_embeddings: HashMap<String, Vec<f32>>, num_messages: usize) -> Vec<Message> {
+/// Load the chat dataset, giving each message up to `context_turns` previous turns of its conversation
+pub fn load_chat_dataset(json_path: &str, msg_size: usize, context_turns: usize, tokenizer: &Tokenizer, embedding: &Embedding, num_messages: usize) -> Vec<Message> {
     let mut chat_dataset = Vec::new();
-    let file = std::fs::File::open(json_path).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let json: serde_json::Value = serde_json::from_reader(reader).unwrap();
-    let messages = json.as_array().unwrap();
+    let messages = load_chat_records(json_path);
     let mut count = 0;
+    let mut num_words = 0;
+    let mut num_unknown = 0;
+    let mut num_composed = 0;
+    let mut conversation_id = None;
+    let mut history: Vec<Array1<String>> = Vec::new();
     for message in messages {
-        let cleaned = clean_msg(message["content"].as_str().unwrap().to_string(), word_embeddings.clone());
-        if cleaned.len() == 0 {
-            continue;
+        // Messages are stored in order, so a new conversation id starts a new history
+        if message.conversation_id.is_none() || message.conversation_id != conversation_id {
+            history.clear();
+            conversation_id = message.conversation_id.clone();
         }
-        let msg = pad_msg(cleaned, msg_size);
-        let author_role = message["role"].as_str().unwrap();
-        let author = if author_role == "user" {
-            0
-        } else if author_role == "assistant" {
-            1
-        } else {
+
+        let cleaned = tokenizer.tokenize(&message.content);
+        if cleaned.is_empty() {
             continue;
+        }
+        let words = pad_msg(cleaned, msg_size);
+        // Count the out-of-vocabulary words which the model will see
+        for word in words.iter().filter(|word| *word != PAD_TOKEN) {
+            num_words += 1;
+            if !embedding.contains(word) {
+                num_unknown += 1;
+                if embedding.can_compose(word) {
+                    num_composed += 1;
+                }
+            }
+        }
+        let context = history[history.len().saturating_sub(context_turns)..].to_vec();
+        history.push(words.clone());
+
+        let author = match AUTHOR_NAMES.iter().position(|name| *name == message.role) {
+            Some(author) => author,
+            None => continue,
         };
         let chat_msg = Message {
-            msg,
+            input: MessageInput::new(words, message.content, context),
             author,
         };
         chat_dataset.push(chat_msg);
@@ -85,5 +103,9 @@ pub fn load_chat_dataset(json_path: &str, msg_size: usize, word_embeddings: Hash
             break;
         }
     }
+    // An empty dataset has no words, so report a rate of zero rather than NaN
+    let oov_rate = if num_words > 0 { num_unknown as Float / num_words as Float } else { 0.0 };
+    info!("Out-of-vocabulary rate: {:.4} ({} of {} words)", oov_rate, num_unknown, num_words);
+    info!("Composed {} out-of-vocabulary words from character n-grams", num_composed);
     chat_dataset
 }
\ No newline at end of file
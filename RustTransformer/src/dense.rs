@@ -1,77 +1,143 @@
-use ndarray::{Array1, Array2};
+use ndarray::{Array1, Array2, ArrayViewMutD, Axis};
+use ndarray::linalg::general_mat_mul;
+use crate::activation::Activation;
 use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::quantize::{Quantize, QuantizedMatrix};
+use crate::{cast, BlockFloat, Float, FloatStorage};
 use crate::LR;
 use rand_distr::{Distribution, Normal};
 use serde::{Serialize, Deserialize};
 
 // Defines struct for storing dense parameters
-#[derive(Serialize, Deserialize)]
-pub struct DenseParams {
-    weights: Vec<Array2::<f32>>,
-    biases: Vec<Array1::<f32>>,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DenseParams<F = Float> {
+    weights: Vec<Array2::<F>>,
+    biases: Vec<Array1::<F>>,
 }
 
 // Defines dense layer struct
-#[derive(Serialize, Deserialize)]
-pub struct Dense {
-    input: Array1::<f32>,
+#[derive(Serialize, Deserialize, Clone)]
+// The missing fields of older models are filled in with `None`, which needs no bound on F
+#[serde(bound(deserialize = "F: Deserialize<'de>"))]
+pub struct Dense<F = Float> {
+    input: Array1::<F>,
     pub input_size: usize,
-    linear: bool,
-    classifier: bool,
-    layer: Vec<Array1::<f32>>,
-    error: Vec<Array1::<f32>>,
-    params: DenseParams,
+    activations: Vec<Activation>,
+    weighted: Vec<Array1::<F>>,
+    layer: Vec<Array1::<F>>,
+    error: Vec<Array1::<F>>,
+    params: DenseParams<F>,
+    #[serde(default)]
+    quantized: Option<Vec<QuantizedMatrix<F>>>,
 }
 
-impl Dense {
-    /// Create a new self-attention block with the given parameters
-    pub fn new(layer_sizes: Array1<usize>, linear: bool, classifier: bool) -> Dense {
-        let input = Array1::<f32>::zeros(layer_sizes[0]);
+impl<F: FloatStorage> Dense<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> Dense<G> {
+        let cast_all = |arrays: &Vec<Array1<F>>| arrays.iter().map(|array| array.mapv(cast)).collect();
+        Dense {
+            input: self.input.mapv(cast),
+            input_size: self.input_size,
+            activations: self.activations.clone(),
+            weighted: cast_all(&self.weighted),
+            layer: cast_all(&self.layer),
+            error: cast_all(&self.error),
+            params: DenseParams {
+                weights: self.params.weights.iter().map(|weights| weights.mapv(cast)).collect(),
+                biases: cast_all(&self.params.biases),
+            },
+            quantized: self.quantized.as_ref().map(|quantized| quantized.iter().map(QuantizedMatrix::cast).collect()),
+        }
+    }
+}
+
+impl<F: BlockFloat> Dense<F> {
+    /// Create a new dense block which applies the same activation after every layer
+    pub fn new(layer_sizes: Array1<usize>, activation: Activation) -> Dense<F> {
+        let activations = vec![activation; layer_sizes.len()-1];
+        Dense::with_activations(layer_sizes, activations)
+    }
+
+    /// Create a new dense block with a separate activation after each layer
+    pub fn with_activations(layer_sizes: Array1<usize>, activations: Vec<Activation>) -> Dense<F> {
+        assert_eq!(activations.len(), layer_sizes.len()-1, "Expected one activation per layer of weights");
+        // The softmax derivative assumes the error is already with respect to its input, which
+        // only holds for an output layer trained with cross entropy
+        assert!(!activations[..activations.len()-1].contains(&Activation::Softmax), "Softmax can only be the activation of the output layer");
+
+        let input = Array1::<F>::zeros(layer_sizes[0]);
         let mut layer = vec![];
         let mut error = vec![];
         let mut weights = vec![];
-        let mut biases = vec![Array1::<f32>::zeros(0)];
+        let mut biases = vec![Array1::<F>::zeros(0)];
 
         for i in 0..layer_sizes.len()-1 {
-            let normal = Normal::new(0.0, (2.0 / layer_sizes[i] as f32).sqrt()).unwrap();
-            let mut layer_weights = Array2::<f32>::zeros((layer_sizes[i],layer_sizes[i+1]));
-            let mut layer_biases = Array1::<f32>::zeros(layer_sizes[i+1]);
+            let normal = Normal::new(0.0, (2.0 / layer_sizes[i] as f64).sqrt()).unwrap();
+            let mut layer_weights = Array2::<F>::zeros((layer_sizes[i],layer_sizes[i+1]));
+            let mut layer_biases = Array1::<F>::zeros(layer_sizes[i+1]);
 
             // Use He initialisation by using a mean of 0.0 and a standard deviation of sqrt(2/n)
-            layer_weights.mapv_inplace(|_| normal.sample(&mut rand::thread_rng()));
-            layer_biases.mapv_inplace(|_| normal.sample(&mut rand::thread_rng()));
+            layer_weights.mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
+            layer_biases.mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
 
             weights.push(layer_weights);
             biases.push(layer_biases);
-            layer.push(Array1::<f32>::zeros(layer_sizes[i]));
-            error.push(Array1::<f32>::zeros(layer_sizes[i]));
+            layer.push(Array1::<F>::zeros(layer_sizes[i]));
+            error.push(Array1::<F>::zeros(layer_sizes[i]));
         }
 
-        layer.push(Array1::<f32>::zeros(layer_sizes[layer_sizes.len()-1]));
-        error.push(Array1::<f32>::zeros(layer_sizes[layer_sizes.len()-1]));
+        layer.push(Array1::<F>::zeros(layer_sizes[layer_sizes.len()-1]));
+        error.push(Array1::<F>::zeros(layer_sizes[layer_sizes.len()-1]));
+        let weighted = layer.clone();
 
         let params = DenseParams { weights, biases };
 
-        let block: Dense = Dense {
+        let block: Dense<F> = Dense {
             input,
             input_size: layer_sizes[0],
-            linear,
-            classifier,
+            activations,
+            weighted,
             layer,
             error,
-            params
+            params,
+            quantized: None,
         };
 
         block
     }
+
+    /// The weighted sums of the output layer from the last forward pass, before its activation
+    pub fn logits(&self) -> &Array1<F> {
+        &self.weighted[self.weighted.len() - 1]
+    }
+
+    /// The weighted sums of the output layer for the given input, without storing anything
+    pub fn infer_logits(&self, value: Array1<F>) -> Array1<F> {
+        let num_layers = self.params.weights.len();
+        let mut layer = value;
+        for i in 0..num_layers - 1 {
+            layer = self.activations[i].apply(self.infer_weighted_sum(&layer, i));
+        }
+        self.infer_weighted_sum(&layer, num_layers - 1)
+    }
+
+    /// The weighted sum of a layer during inference, using the int8 weights once quantized
+    fn infer_weighted_sum(&self, layer: &Array1<F>, index: usize) -> Array1<F> {
+        let weighted_sum = match self.quantized.as_ref() {
+            Some(quantized) => quantized[index].vector_product(layer),
+            None => layer.dot(&self.params.weights[index]),
+        };
+        weighted_sum + &self.params.biases[index + 1]
+    }
 }
 
-pub fn softmax(x: Array1<f32>) -> Array1<f32> {
-    let mut sum: f32 = 0.0;
-    let mut output = Array1::<f32>::zeros(x.len());
+pub fn softmax<F: BlockFloat>(x: Array1<F>) -> Array1<F> {
+    let mut sum = F::zero();
+    let mut output = Array1::<F>::zeros(x.len());
 
     // remove the largest value from each element to prevent overflow
-    let max = x.iter().fold(f32::MIN, |a, &b| a.max(b));
+    let max = x.iter().fold(F::min_value(), |a, &b| a.max(b));
     let x = x.mapv(|x| x - max);
 
     for i in 0..x.len() {
@@ -85,11 +151,12 @@ pub fn softmax(x: Array1<f32>) -> Array1<f32> {
     output
 }
 
-impl Block for Dense {
-    type Input = Array1<f32>;
-    type Output = Array1<f32>;
+impl<F: BlockFloat> Block for Dense<F> {
+    type Input = Array1<F>;
+    type Output = Array1<F>;
 
     fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        assert!(self.quantized.is_none(), "A quantized dense layer can only be used for inference");
         self.input = value;
 
         // Assign input values to the first layer
@@ -100,54 +167,141 @@ impl Block for Dense {
             // Compute the weighted sum of the previous layer's output
             let weighted_sum = &self.layer[i - 1].dot(&self.params.weights[i - 1]);
 
-            self.layer[i] = weighted_sum + &self.params.biases[i];
+            // Store the weighted sum for the derivative of the activation function
+            self.weighted[i] = weighted_sum + &self.params.biases[i];
 
-            // Apply activation function if not using linear activation
-            if !self.linear {
-                if self.classifier {
-                    // Apply softmax activation function for classifier networks
-                    self.layer[i] = softmax(self.layer[i].clone());
-                } else {
-                    // Apply ReLU activation function for non-classifier networks
-                    self.layer[i].mapv_inplace(|x| if x > 0.0 { x } else { 0.0 });
-                }
-            }
+            // Apply the layer's activation function
+            self.layer[i] = self.activations[i - 1].apply(self.weighted[i].clone());
         }
 
         // Return the output of the last layer
         self.layer[self.layer.len() - 1].clone()
     }
 
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        self.activations[self.activations.len() - 1].apply(self.infer_logits(value))
+    }
+
     fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        let lr = F::constant(LR);
+
         // Set the error of the output layer
         self.error[self.layer.len()-1] = error;
 
-        // Iterate through the layers in reverse order, excluding the output layer
+        // Iterate through the layers in reverse order, excluding the input layer
         for i in 0..self.layer.len()-1 {
             let index: usize = self.layer.len() - (i+2);
 
-            // Iterate through the neurons in the current layer
-            for j in 0..self.layer[index].len() {
-                self.error[index][j] = 0.0;
+            // Apply the derivative of the next layer's activation function to find the error of its weighted sum
+            let derivative = self.activations[index].derivative(&self.weighted[index+1], &self.layer[index+1]);
+            self.error[index+1] = &self.error[index+1] * &derivative;
 
-                // Iterate through the neurons in the next layer
-                for k in 0..self.layer[index+1].len() {
-                    // Calculate error and update weights
-                    let next_error: f32 = self.error[index+1][k];
-                    self.error[index][j] += self.params.weights[index][[j,k]] * next_error;
-                    self.params.weights[index][[j,k]] -= self.layer[index][j] * next_error * LR;
-                }
-                // The first layer did not have an activation function nor biases
-                if index > 0 {
-                    // Apply the derivative of the relevant activation function
-                    if !self.classifier && self.layer[index][j] <= 0.0 {
-                        self.error[index][j] = 0.0;
-                    }
-                    self.params.biases[index][j] -= self.error[index][j] * LR;
+            // Update the biases of the next layer
+            self.params.biases[index+1].scaled_add(-lr, &self.error[index+1]);
+
+            // Calculate the error of the current layer using the weights before they are updated
+            self.error[index] = self.params.weights[index].dot(&self.error[index+1]);
+
+            // Update the weights with the outer product of the current layer and the next layer's error
+            let layer = self.layer[index].view().insert_axis(Axis(1));
+            let next_error = self.error[index+1].view().insert_axis(Axis(0));
+            general_mat_mul(-lr, &layer, &next_error, F::one(), &mut self.params.weights[index]);
+        }
+
+        self.error[0].clone()
+    }
+}
+
+impl<F: BlockFloat> Parameters<F> for Dense<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        let weights = self.params.weights.iter_mut().map(|weights| weights.view_mut().into_dyn());
+        let biases = self.params.biases.iter_mut().map(|biases| biases.view_mut().into_dyn());
+        weights.chain(biases).collect()
+    }
+}
+
+impl<F: BlockFloat> Quantize for Dense<F> {
+    fn quantize(&mut self) {
+        if self.quantized.is_some() {
+            return;
+        }
+        self.quantized = Some(self.params.weights.iter().map(QuantizedMatrix::new).collect());
+        for weights in self.params.weights.iter_mut() {
+            *weights = Array2::<F>::zeros((0, 0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{arr1, Array1};
+    use rand::Rng;
+    use super::Dense;
+    use crate::activation::Activation;
+    use crate::block::Block;
+    use crate::block::tests::check_gradients;
+    use crate::{Float, LR};
+
+    /// Back propagation with the element-wise loops the vectorised version replaced
+    fn loop_back_propagate(dense: &mut Dense, error: Array1<Float>) -> Array1<Float> {
+        dense.error[dense.layer.len()-1] = error;
+        for i in 0..dense.layer.len()-1 {
+            let index: usize = dense.layer.len() - (i+2);
+            let derivative = dense.activations[index].derivative(&dense.weighted[index+1], &dense.layer[index+1]);
+            dense.error[index+1] = &dense.error[index+1] * &derivative;
+
+            for k in 0..dense.layer[index+1].len() {
+                dense.params.biases[index+1][k] -= dense.error[index+1][k] * LR;
+            }
+            for j in 0..dense.layer[index].len() {
+                dense.error[index][j] = 0.0;
+                for k in 0..dense.layer[index+1].len() {
+                    let next_error = dense.error[index+1][k];
+                    dense.error[index][j] += dense.params.weights[index][[j,k]] * next_error;
+                    dense.params.weights[index][[j,k]] -= dense.layer[index][j] * next_error * LR;
                 }
             }
         }
 
-        self.error[0].clone()
+        dense.error[0].clone()
     }
-}
\ No newline at end of file
+
+    fn random_vector(len: usize) -> Array1<Float> {
+        let mut rng = rand::thread_rng();
+        Array1::from_shape_fn(len, |_| rng.gen::<Float>() * 2.0 - 1.0)
+    }
+
+    #[test]
+    fn back_propagation_matches_the_loops() {
+        let mut vectorised = Dense::new(arr1(&[12, 8, 5]), Activation::Relu);
+        let mut looped = vectorised.clone();
+        let input = random_vector(12);
+        let error = random_vector(5);
+
+        vectorised.forward_propagate(input.clone());
+        looped.forward_propagate(input);
+        let vectorised_error = vectorised.back_propagate(error.clone());
+        let looped_error = loop_back_propagate(&mut looped, error);
+
+        let close = |a: &Float, b: &Float| (a - b).abs() <= 1e-5 * (1.0 + b.abs());
+        assert!(vectorised_error.iter().zip(looped_error.iter()).all(|(a, b)| close(a, b)));
+        for (a, b) in vectorised.params.weights.iter().zip(looped.params.weights.iter()) {
+            assert!(a.iter().zip(b.iter()).all(|(a, b)| close(a, b)));
+        }
+        for (a, b) in vectorised.params.biases.iter().zip(looped.params.biases.iter()) {
+            assert!(a.iter().zip(b.iter()).all(|(a, b)| close(a, b)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Softmax can only be the activation of the output layer")]
+    fn softmax_is_rejected_on_hidden_layers() {
+        Dense::<Float>::with_activations(arr1(&[4, 3, 2]), vec![Activation::Softmax, Activation::Softmax]);
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        let dense = Dense::with_activations(arr1(&[6, 5, 4]), vec![Activation::Gelu, Activation::Tanh]);
+        check_gradients(&dense, &random_vector(6), 2e-2);
+    }
+}
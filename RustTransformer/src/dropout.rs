@@ -0,0 +1,70 @@
+use ndarray::Array2;
+use crate::block::Block;
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+// Defines a dropout struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Dropout<F = Float> {
+    mask: Array2::<F>,
+    rate: F,
+    training: bool,
+}
+
+impl<F: FloatStorage> Dropout<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> Dropout<G> {
+        Dropout { mask: self.mask.mapv(cast), rate: cast(self.rate), training: self.training }
+    }
+}
+
+impl<F: BlockFloat> Dropout<F> {
+    /// Create a new dropout block which zeroes each element with probability `rate`
+    pub fn new(rows: usize, cols: usize, rate: Float) -> Dropout<F> {
+        assert!((0.0..1.0).contains(&rate), "Dropout rate must be in [0, 1)");
+
+        let block: Dropout<F> = Dropout {
+            mask: Array2::<F>::ones((rows, cols)),
+            rate: F::constant(rate),
+            training: true,
+        };
+
+        block
+    }
+
+    /// Switch between training, where elements are dropped, and evaluation, where the block is the identity
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+impl<F: BlockFloat> Block for Dropout<F> {
+    type Input = Array2<F>;
+    type Output = Array2<F>;
+
+    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        if !self.training || self.rate == F::zero() {
+            self.mask = Array2::<F>::ones(value.raw_dim());
+            return value;
+        }
+
+        // Scale the kept elements so the expected output matches evaluation mode
+        let scale = F::one() / (F::one() - self.rate);
+        let rate = self.rate.widen();
+        let mut rng = rand::thread_rng();
+        self.mask = Array2::from_shape_fn(value.raw_dim(), |_| if rng.gen::<f64>() < rate { F::zero() } else { scale });
+
+        value * &self.mask
+    }
+
+    fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        // Only the elements which were kept pass their error back
+        error * &self.mask
+    }
+
+    /// Nothing is dropped in evaluation mode
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        value
+    }
+}
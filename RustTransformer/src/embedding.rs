@@ -1,26 +1,408 @@
+use ndarray::{s, Array1, Array2, ArrayViewMutD, Axis};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
+use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::config::TransformerConfig;
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use crate::LR;
+use rand_distr::{Distribution, Normal};
 use serde::{Serialize, Deserialize};
 use log::info;
+use chat_core::embedding_file;
 
-// Defines the embedding object
-#[derive(Serialize, Deserialize)]
-pub struct Embedding {
-    data: HashMap<String, Vec<f32>>,
-}
+/// Token used to pad messages to a fixed number of words
+pub const PAD_TOKEN: &str = "<pad>";
+/// Token used for words which have no vector
+pub const UNK_TOKEN: &str = "<unk>";
+/// Token used to separate the turns of a conversation
+pub const SEP_TOKEN: &str = "<sep>";
 
-pub fn load_embeddings(file_name: &str) -> HashMap<String, Vec<f32>> {
-    let mut file = File::open(file_name).expect("Failed to open file");
-    let mut serialized = String::new();
-    file.read_to_string(&mut serialized).expect("Failed to read file");
+const PAD_ID: usize = 0;
+const UNK_ID: usize = 1;
+const SEP_ID: usize = 2;
+/// The number of rows reserved for special tokens at the start of the matrix
+const NUM_RESERVED: usize = 3;
 
-    // Deserialize the embeddings into a HashMap.
-    let deserialized: Embedding = serde_json::from_str(&serialized).unwrap();
-    let mut embeddings: HashMap<String, Vec<f32>> = deserialized.data.clone();
-    embeddings.insert("".to_string(), vec![0.0; 150]);
+pub fn load_embeddings(file_name: &str) -> HashMap<String, Vec<f32>> {
+    let embeddings = embedding_file::load_embeddings(file_name);
 
     info!("Loaded {} word embeddings successfully.", embeddings.len());
 
     embeddings
-}
\ No newline at end of file
+}
+
+/// How the word vectors are trained alongside the rest of the model
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbeddingMode {
+    /// Keep the pre-trained vectors fixed
+    Frozen,
+    /// Start from the pre-trained vectors and update them during training
+    FineTune,
+    /// Keep the pre-trained vocabulary but learn the vectors from random values
+    FromScratch,
+}
+
+/// The vector used for out-of-vocabulary words
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownVector {
+    /// A fixed vector of zeros
+    Zeros,
+    /// A fixed vector at the mean of the vocabulary
+    Mean,
+    /// A vector starting at the mean of the vocabulary which is trained in every mode
+    Learned,
+}
+
+// Defines a character n-gram table for composing vectors of unseen words
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NgramFallback<F = Float> {
+    min_n: usize,
+    max_n: usize,
+    vectors: HashMap<String, Array1::<F>>,
+}
+
+impl<F: FloatStorage> NgramFallback<F> {
+    /// Convert every vector to another float type
+    fn cast<G: FloatStorage>(&self) -> NgramFallback<G> {
+        let vectors = self.vectors.iter().map(|(ngram, vector)| (ngram.clone(), vector.mapv(cast))).collect();
+        NgramFallback { min_n: self.min_n, max_n: self.max_n, vectors }
+    }
+}
+
+impl<F: BlockFloat> NgramFallback<F> {
+    /// Build the n-gram table by averaging the vectors of every word containing each n-gram
+    fn new(vocab: &HashMap<String, usize>, matrix: &Array2<F>, min_n: usize, max_n: usize) -> NgramFallback<F> {
+        let mut sums: HashMap<String, (Array1<F>, F)> = HashMap::new();
+
+        for (word, &id) in vocab {
+            if id < NUM_RESERVED {
+                continue;
+            }
+            for ngram in ngrams(word, min_n, max_n) {
+                let entry = sums.entry(ngram).or_insert_with(|| (Array1::<F>::zeros(matrix.shape()[1]), F::zero()));
+                entry.0 += &matrix.row(id);
+                entry.1 += F::one();
+            }
+        }
+
+        let vectors = sums.into_iter().map(|(ngram, (sum, count))| (ngram, sum / count)).collect();
+
+        NgramFallback { min_n, max_n, vectors }
+    }
+
+    /// Compose a vector for a word from the mean of its known n-grams
+    pub fn compose(&self, word: &str) -> Option<Array1<F>> {
+        let known: Vec<&Array1<F>> = ngrams(word, self.min_n, self.max_n).iter().filter_map(|ngram| self.vectors.get(ngram)).collect();
+        if known.is_empty() {
+            return None;
+        }
+
+        let mut vector = Array1::<F>::zeros(known[0].len());
+        for ngram_vector in &known {
+            vector += *ngram_vector;
+        }
+
+        Some(vector / F::constant(known.len()))
+    }
+}
+
+/// Split a word into its character n-grams, marking the start and end of the word
+fn ngrams(word: &str, min_n: usize, max_n: usize) -> Vec<String> {
+    let chars: Vec<char> = format!("<{}>", word).chars().collect();
+    let mut ngrams = Vec::new();
+
+    for n in min_n..=max_n {
+        for window in chars.windows(n) {
+            ngrams.push(window.iter().collect());
+        }
+    }
+
+    ngrams
+}
+
+// Defines an embedding struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Embedding<F = Float> {
+    input: Array1::<String>,
+    ids: Array1::<Option<usize>>,
+    vocab: HashMap<String, usize>,
+    mode: EmbeddingMode,
+    unknown: UnknownVector,
+    ngram_fallback: Option<NgramFallback<F>>,
+    matrix: Array2::<F>,
+}
+
+impl<F: FloatStorage> Embedding<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> Embedding<G> {
+        Embedding {
+            input: self.input.clone(),
+            ids: self.ids.clone(),
+            vocab: self.vocab.clone(),
+            mode: self.mode,
+            unknown: self.unknown,
+            ngram_fallback: self.ngram_fallback.as_ref().map(NgramFallback::cast),
+            matrix: self.matrix.mapv(cast),
+        }
+    }
+}
+
+impl<F: BlockFloat> Embedding<F> {
+    /// Create a new embedding block from a map of word vectors. Vectors are
+    /// truncated or zero-padded to the given dimensionality. Words are given ids in
+    /// alphabetical order, so the same vectors always give the same matrix.
+    pub fn new(mut vectors: HashMap<String, Vec<f32>>, config: &TransformerConfig) -> Embedding<F> {
+        // Vectors for the special tokens would overwrite their reserved ids, so they are skipped
+        for token in [PAD_TOKEN, UNK_TOKEN, SEP_TOKEN] {
+            if vectors.remove(token).is_some() {
+                info!("Skipped the vector of the reserved token {}", token);
+            }
+        }
+
+        let dimensionality = config.dimensionality;
+        let mut vocab = HashMap::with_capacity(vectors.len() + NUM_RESERVED);
+        let mut matrix = Array2::<F>::zeros((vectors.len() + NUM_RESERVED, dimensionality));
+
+        // Reserve the first rows for the padding, unknown and separator tokens
+        vocab.insert(PAD_TOKEN.to_string(), PAD_ID);
+        vocab.insert(UNK_TOKEN.to_string(), UNK_ID);
+        vocab.insert(SEP_TOKEN.to_string(), SEP_ID);
+
+        // A HashMap iterates in a different order every run, so the words are sorted first
+        let mut vectors: Vec<(String, Vec<f32>)> = vectors.into_iter().collect();
+        vectors.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        for (word, vector) in vectors.into_iter() {
+            let id = vocab.len();
+            for (j, value) in vector.into_iter().take(dimensionality).enumerate() {
+                matrix[[id, j]] = F::constant(value);
+            }
+            vocab.insert(word, id);
+        }
+
+        let normal = Normal::new(0.0, (1.0 / dimensionality as f64).sqrt()).unwrap();
+        if config.embedding_mode == EmbeddingMode::FromScratch {
+            matrix.mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
+            matrix.row_mut(PAD_ID).fill(F::zero());
+        }
+
+        // The separator has no pre-trained vector, so it always starts from random values
+        matrix.row_mut(SEP_ID).mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
+
+        // Padding always embeds to zeros, and the unknown vector is filled in from the rest of the vocabulary
+        if config.unknown_vector != UnknownVector::Zeros && matrix.nrows() > NUM_RESERVED {
+            let mean = matrix.slice(s![NUM_RESERVED.., ..]).mean_axis(Axis(0)).unwrap();
+            matrix.row_mut(UNK_ID).assign(&mean);
+        }
+
+        let ngram_fallback = config.ngram_fallback.then(|| NgramFallback::new(&vocab, &matrix, 3, 5));
+
+        let block: Embedding<F> = Embedding {
+            input: Array1::<String>::default(0),
+            ids: Array1::<Option<usize>>::default(0),
+            vocab,
+            mode: config.embedding_mode,
+            unknown: config.unknown_vector,
+            ngram_fallback,
+            matrix,
+        };
+
+        block
+    }
+
+    /// Create a new embedding block from the output file of WordEmbeddings
+    pub fn from_file(file_name: &str, config: &TransformerConfig) -> Embedding<F> {
+        Embedding::new(load_embeddings(file_name), config)
+    }
+
+    /// Whether the word has a vector in the embedding
+    pub fn contains(&self, word: &str) -> bool {
+        self.vocab.contains_key(word)
+    }
+
+    /// Whether an out-of-vocabulary word can be composed from its character n-grams
+    pub fn can_compose(&self, word: &str) -> bool {
+        self.ngram_fallback.as_ref().is_some_and(|fallback| fallback.compose(word).is_some())
+    }
+
+    /// The number of words in the vocabulary, including the special tokens
+    pub fn len(&self) -> usize {
+        self.vocab.len()
+    }
+
+    /// Whether the vocabulary is empty
+    pub fn is_empty(&self) -> bool {
+        self.vocab.is_empty()
+    }
+
+    /// Look up the vector of each word, along with the row of the matrix it came from
+    fn lookup(&self, words: &Array1<String>) -> (Array2<F>, Array1<Option<usize>>) {
+        let mut output = Array2::<F>::zeros((words.len(), self.matrix.ncols()));
+        let mut ids = Array1::from_elem(words.len(), None);
+
+        for (i, word) in words.iter().enumerate() {
+            if let Some(&id) = self.vocab.get(word) {
+                ids[i] = Some(id);
+                output.row_mut(i).assign(&self.matrix.row(id));
+                continue;
+            }
+
+            // Composed vectors are not trained, as they are not stored in the matrix
+            match self.ngram_fallback.as_ref().and_then(|fallback| fallback.compose(word)) {
+                Some(vector) => output.row_mut(i).assign(&vector),
+                None => {
+                    ids[i] = Some(UNK_ID);
+                    output.row_mut(i).assign(&self.matrix.row(UNK_ID));
+                }
+            }
+        }
+
+        (output, ids)
+    }
+
+    /// Whether the row with the given id is updated during back propagation
+    fn is_trainable(&self, id: usize) -> bool {
+        match id {
+            PAD_ID => false,
+            UNK_ID => self.unknown == UnknownVector::Learned,
+            SEP_ID => true,
+            _ => self.mode != EmbeddingMode::Frozen,
+        }
+    }
+}
+
+impl<F: BlockFloat> Block for Embedding<F> {
+    type Input = Array1<String>;
+    type Output = Array2<F>;
+
+    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        // Store the id of each word to update the same rows during back propagation
+        let (output, ids) = self.lookup(&value);
+        self.input = value;
+        self.ids = ids;
+
+        output
+    }
+
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        self.lookup(&value).0
+    }
+
+    /// Words have no error, so the input is returned unchanged.
+    fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        // Update the vector of each word in the input
+        let lr = F::constant(LR);
+        for (i, id) in self.ids.iter().enumerate() {
+            if let Some(id) = *id {
+                if self.is_trainable(id) {
+                    self.matrix.row_mut(id).scaled_add(-lr, &error.row(i));
+                }
+            }
+        }
+
+        self.input.clone()
+    }
+}
+
+impl<F: BlockFloat> Parameters<F> for Embedding<F> {
+    /// Frozen vectors are never updated, so only the rows of the special tokens are trained
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        match self.mode {
+            EmbeddingMode::Frozen => vec![self.matrix.slice_mut(s![..NUM_RESERVED, ..]).into_dyn()],
+            EmbeddingMode::FineTune | EmbeddingMode::FromScratch => vec![self.matrix.view_mut().into_dyn()],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use ndarray::{arr1, arr2, s, Array1};
+    use super::{Embedding, EmbeddingMode, UnknownVector, PAD_ID, PAD_TOKEN, UNK_ID, UNK_TOKEN, NUM_RESERVED};
+    use crate::block::Block;
+    use crate::config::TransformerConfig;
+
+    fn embedding(unknown_vector: UnknownVector, ngram_fallback: bool, extra: &[(&str, Vec<f32>)]) -> Embedding {
+        let mut vectors: HashMap<String, Vec<f32>> = HashMap::new();
+        vectors.insert("cat".to_string(), vec![1.0, 2.0]);
+        vectors.insert("dog".to_string(), vec![3.0, 4.0]);
+        for (word, vector) in extra {
+            vectors.insert(word.to_string(), vector.clone());
+        }
+        let config = TransformerConfig { dimensionality: 2, unknown_vector, ngram_fallback, ..TransformerConfig::default() };
+        Embedding::new(vectors, &config)
+    }
+
+    fn words(words: &[&str]) -> Array1<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn reserved_tokens_in_the_file_are_skipped() {
+        let embedding = embedding(UnknownVector::Zeros, false, &[(PAD_TOKEN, vec![9.0, 9.0]), (UNK_TOKEN, vec![8.0, 8.0])]);
+        assert_eq!(embedding.len(), NUM_RESERVED + 2);
+        assert_eq!(embedding.matrix.nrows(), NUM_RESERVED + 2);
+        assert_eq!(embedding.vocab[PAD_TOKEN], PAD_ID);
+        assert_eq!(embedding.vocab[UNK_TOKEN], UNK_ID);
+        assert!(embedding.matrix.row(PAD_ID).iter().all(|&x| x == 0.0));
+        assert!(embedding.matrix.row(UNK_ID).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn words_are_given_ids_in_alphabetical_order() {
+        let extra = [("zebra", vec![5.0, 6.0]), ("ant", vec![7.0, 8.0])];
+        let first = embedding(UnknownVector::Zeros, false, &extra);
+        assert_eq!(first.vocab["ant"], NUM_RESERVED);
+        assert_eq!(first.vocab["cat"], NUM_RESERVED + 1);
+        assert_eq!(first.vocab["dog"], NUM_RESERVED + 2);
+        assert_eq!(first.vocab["zebra"], NUM_RESERVED + 3);
+        // A HashMap built again iterates in a different order, but gives the same matrix
+        let second = embedding(UnknownVector::Zeros, false, &extra);
+        assert_eq!(second.matrix.slice(s![NUM_RESERVED.., ..]), first.matrix.slice(s![NUM_RESERVED.., ..]));
+    }
+
+    #[test]
+    fn unknown_words_use_the_unknown_vector() {
+        let zeros = embedding(UnknownVector::Zeros, false, &[]);
+        assert_eq!(zeros.infer(words(&["cat", "zebra"])), arr2(&[[1.0, 2.0], [0.0, 0.0]]));
+
+        let mean = embedding(UnknownVector::Mean, false, &[]);
+        assert_eq!(mean.infer(words(&["dog", "zebra"])), arr2(&[[3.0, 4.0], [2.0, 3.0]]));
+    }
+
+    #[test]
+    fn only_a_learned_unknown_vector_is_trained() {
+        for (unknown_vector, trained) in [(UnknownVector::Zeros, false), (UnknownVector::Mean, false), (UnknownVector::Learned, true)] {
+            let mut embedding = embedding(unknown_vector, false, &[]);
+            let before = embedding.matrix.row(UNK_ID).to_owned();
+            embedding.forward_propagate(words(&["zebra"]));
+            embedding.back_propagate(arr2(&[[1.0, 1.0]]));
+            assert_eq!(embedding.matrix.row(UNK_ID) != before, trained, "{:?}", unknown_vector);
+        }
+    }
+
+    #[test]
+    fn padding_stays_zero() {
+        let mut embedding = embedding(UnknownVector::Learned, false, &[]);
+        let output = embedding.forward_propagate(words(&["cat", PAD_TOKEN]));
+        assert_eq!(output.row(1), arr1(&[0.0, 0.0]));
+        embedding.back_propagate(arr2(&[[1.0, 1.0], [1.0, 1.0]]));
+        assert!(embedding.matrix.row(PAD_ID).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn unseen_words_are_composed_from_ngrams() {
+        let mut embedding = embedding(UnknownVector::Zeros, true, &[]);
+        // "cats" shares "<ca", "cat" and "<cat" with "cat" only, and "zzz" shares nothing
+        assert!(embedding.can_compose("cats"));
+        assert!(!embedding.can_compose("zzz"));
+        assert_eq!(embedding.infer(words(&["cats", "zzz"])), arr2(&[[1.0, 2.0], [0.0, 0.0]]));
+
+        // Composed vectors aren't stored in the matrix, so they aren't trained even when fine-tuning
+        embedding.mode = EmbeddingMode::FineTune;
+        let before = embedding.matrix.clone();
+        embedding.forward_propagate(words(&["cats"]));
+        embedding.back_propagate(arr2(&[[1.0, 1.0]]));
+        assert_eq!(embedding.matrix, before);
+    }
+}
@@ -1,96 +1,261 @@
-use ndarray::{Array1, Array2};
+use ndarray::{arr1, Array2, ArrayViewMutD};
 use crate::add_and_norm::AddAndNorm;
 use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::quantize::Quantize;
+use crate::config::{NormPlacement, TransformerConfig};
+use crate::dropout::Dropout;
 use crate::multi_headed_attention::MultiHeadedAttention;
 use crate::dense::Dense;
+use crate::{cast, BlockFloat, Float, FloatStorage};
 use serde::{Serialize, Deserialize};
 
 // Defines multi headed attention and feed forward blocks.
-#[derive(Serialize, Deserialize)]
-pub struct EncoderBlockParams {
-    multi_headed: MultiHeadedAttention,
-    feed_forward: Dense,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EncoderBlockParams<F = Float> {
+    multi_headed: MultiHeadedAttention<F>,
+    feed_forward: Dense<F>,
 }
 
 // Defines encoder block struct
-#[derive(Serialize, Deserialize)]
-pub struct EncoderBlock {
-    input: Array2::<f32>,
-    add_and_norm: AddAndNorm,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EncoderBlock<F = Float> {
+    input: Array2::<F>,
+    attention_norm: AddAndNorm<F>,
+    feed_forward_norm: AddAndNorm<F>,
+    attention_dropout: Dropout<F>,
+    feed_forward_dropout: Dropout<F>,
+    norm_placement: NormPlacement,
     rows: usize,
     cols: usize,
-    params: EncoderBlockParams,
+    params: EncoderBlockParams<F>,
 }
 
-impl EncoderBlock {
+impl<F: FloatStorage> EncoderBlock<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> EncoderBlock<G> {
+        EncoderBlock {
+            input: self.input.mapv(cast),
+            attention_norm: self.attention_norm.cast(),
+            feed_forward_norm: self.feed_forward_norm.cast(),
+            attention_dropout: self.attention_dropout.cast(),
+            feed_forward_dropout: self.feed_forward_dropout.cast(),
+            norm_placement: self.norm_placement,
+            rows: self.rows,
+            cols: self.cols,
+            params: EncoderBlockParams {
+                multi_headed: self.params.multi_headed.cast(),
+                feed_forward: self.params.feed_forward.cast(),
+            },
+        }
+    }
+}
+
+impl<F: BlockFloat> EncoderBlock<F> {
     /// Create a new encoder block with the given parameters
-    pub fn new(rows: usize, cols: usize, num_heads: usize, layer_sizes: Array1<usize>) -> EncoderBlock {
-        let multi_headed = MultiHeadedAttention::new(num_heads, rows, cols);
-        let add_and_norm = AddAndNorm::new(rows, cols);
-        let feed_forward = Dense::new(layer_sizes, false, false);
+    pub fn new(config: &TransformerConfig) -> EncoderBlock<F> {
+        let rows = config.sequence_length();
+        let cols = config.dimensionality;
+        let multi_headed = MultiHeadedAttention::new(config.num_heads, rows, cols, config.positional_encoding);
+        // Each residual needs its own norm, as both cache their inputs for back propagation
+        let attention_norm = AddAndNorm::new(rows, cols, config.layer_norm_epsilon);
+        let feed_forward_norm = AddAndNorm::new(rows, cols, config.layer_norm_epsilon);
+        let attention_dropout = Dropout::new(rows, cols, config.dropout_rate);
+        let feed_forward_dropout = Dropout::new(rows, cols, config.dropout_rate);
+        let feed_forward = Dense::new(arr1(&[rows*cols, config.hidden_layer_size, rows*cols]), config.feed_forward_activation);
 
         let params = EncoderBlockParams { multi_headed, feed_forward };
 
-        let block: EncoderBlock = EncoderBlock {
-            input: Array2::<f32>::zeros((rows, cols)),
+        let block: EncoderBlock<F> = EncoderBlock {
+            input: Array2::<F>::zeros((rows, cols)),
             rows,
             cols,
-            add_and_norm,
+            attention_norm,
+            feed_forward_norm,
+            attention_dropout,
+            feed_forward_dropout,
+            norm_placement: config.norm_placement,
             params
         };
 
         block
     }
+
+    /// Switch the dropout layers between training and evaluation mode
+    pub fn set_training(&mut self, training: bool) {
+        self.attention_dropout.set_training(training);
+        self.feed_forward_dropout.set_training(training);
+    }
+
+    /// Forward propagates through the feed-forward layer, reshaping to and from its flat input
+    fn feed_forward(&mut self, value: Array2<F>) -> Array2<F> {
+        let flat = value.into_shape(self.rows*self.cols).unwrap();
+        let feed_out = self.params.feed_forward.forward_propagate(flat);
+        feed_out.into_shape([self.rows, self.cols]).unwrap()
+    }
+
+    /// Runs the feed-forward layer in evaluation mode, reshaping to and from its flat input
+    fn infer_feed_forward(&self, value: Array2<F>) -> Array2<F> {
+        let flat = value.into_shape(self.rows*self.cols).unwrap();
+        let feed_out = self.params.feed_forward.infer(flat);
+        feed_out.into_shape([self.rows, self.cols]).unwrap()
+    }
+
+    /// Back propagates through the feed-forward layer, reshaping to and from its flat error
+    fn feed_forward_error(&mut self, error: Array2<F>) -> Array2<F> {
+        let flat_error = error.into_shape(self.rows*self.cols).unwrap();
+        let feed_flat_error = self.params.feed_forward.back_propagate(flat_error);
+        feed_flat_error.into_shape([self.rows, self.cols]).unwrap()
+    }
 }
 
-impl Block for EncoderBlock {
-    type Input = Array2<f32>;
-    type Output = Array2<f32>;
+impl<F: BlockFloat> Block for EncoderBlock<F> {
+    type Input = Array2<F>;
+    type Output = Array2<F>;
 
     fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
         // Set the input value
         self.input = value;
 
-        // Perform forward propagation through the multi-headed layer
-        let multi_out = self.params.multi_headed.forward_propagate(self.input.clone());
+        match self.norm_placement {
+            NormPlacement::Post => {
+                // Perform forward propagation through the multi-headed layer
+                let multi_out = self.params.multi_headed.forward_propagate(self.input.clone());
+                let multi_out = self.attention_dropout.forward_propagate(multi_out);
 
-        // Perform forward propagation through the add-and-norm layer using the input and the output from the multi-headed layer
-        let add_out = self.add_and_norm.forward_propagate((self.input.clone(), multi_out));
-        let add_out_flat = add_out.clone().into_shape(self.rows*self.cols).unwrap();
+                // Perform forward propagation through the attention add-and-norm layer using the input and the output from the multi-headed layer
+                let add_out = self.attention_norm.forward_propagate((self.input.clone(), multi_out));
 
-        // Perform forward propagation through the feed-forward layer using the flattened output from the add-and-norm layer
-        let feed_out = self.params.feed_forward.forward_propagate(add_out_flat);
-        let feed_out_sq = feed_out.into_shape([self.rows, self.cols]).unwrap();
+                // Perform forward propagation through the feed-forward layer using the output from the add-and-norm layer
+                let feed_out = self.feed_forward(add_out.clone());
+                let feed_out = self.feed_forward_dropout.forward_propagate(feed_out);
 
-        // Perform forward propagation through the add-and-norm layer using the output from the feed-forward layer and the output from the previous add-and-norm layer
-        let output = self.add_and_norm.forward_propagate((add_out, feed_out_sq));
+                // Perform forward propagation through the feed-forward add-and-norm layer using the output from the feed-forward layer and the output from the previous add-and-norm layer
+                self.feed_forward_norm.forward_propagate((add_out, feed_out))
+            }
+            NormPlacement::Pre => {
+                // Normalise the input before the multi-headed layer and add the residual unnormalised
+                let norm_out = self.attention_norm.norm.forward_propagate(self.input.clone());
+                let multi_out = self.params.multi_headed.forward_propagate(norm_out);
+                let multi_out = self.attention_dropout.forward_propagate(multi_out);
+                let add_out = &self.input + &multi_out;
 
-        // Return the final output
-        output
+                // Normalise again before the feed-forward layer
+                let norm_out = self.feed_forward_norm.norm.forward_propagate(add_out.clone());
+                let feed_out = self.feed_forward(norm_out);
+                let feed_out = self.feed_forward_dropout.forward_propagate(feed_out);
+
+                add_out + feed_out
+            }
+        }
+    }
+
+    /// Dropout is the identity in evaluation mode, so it is skipped
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        match self.norm_placement {
+            NormPlacement::Post => {
+                let multi_out = self.params.multi_headed.infer(value.clone());
+                let add_out = self.attention_norm.infer((value, multi_out));
+                let feed_out = self.infer_feed_forward(add_out.clone());
+                self.feed_forward_norm.infer((add_out, feed_out))
+            }
+            NormPlacement::Pre => {
+                let multi_out = self.params.multi_headed.infer(self.attention_norm.norm.infer(value.clone()));
+                let add_out = value + multi_out;
+                let feed_out = self.infer_feed_forward(self.feed_forward_norm.norm.infer(add_out.clone()));
+                add_out + feed_out
+            }
+        }
     }
 
     fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
-        // Backpropagate the error through the `add_and_norm` layer, then reshape
-        let norm_error = self.add_and_norm.back_propagate(error);
-        let flat_error = norm_error.1.into_shape(self.rows * self.cols).unwrap();
+        match self.norm_placement {
+            NormPlacement::Post => {
+                // Backpropagate the error through the `feed_forward_norm` layer
+                let norm_error = self.feed_forward_norm.back_propagate(error);
 
-        // Backpropagate the flat error through the `feed_forward` layer, then reshape it to a 2D array
-        let feed_flat_error = self.params.feed_forward.back_propagate(flat_error);
-        let feed_error = feed_flat_error.into_shape([self.rows, self.cols]).unwrap();
+                // Backpropagate the error through the `feed_forward_dropout` and `feed_forward` layers
+                let dropout_error = self.feed_forward_dropout.back_propagate(norm_error.1);
+                let feed_error = self.feed_forward_error(dropout_error);
 
-        // Combine the error from the `add_and_norm` layer and the `feed_forward` layer
-        let residual_error = &norm_error.0 + &feed_error;
+                // Combine the error from the `feed_forward_norm` layer and the `feed_forward` layer
+                let residual_error = &norm_error.0 + &feed_error;
 
-        // Backpropagate the residual error through the `add_and_norm` layer
-        let norm_error2 = self.add_and_norm.back_propagate(residual_error);
+                // Backpropagate the residual error through the `attention_norm` layer
+                let norm_error2 = self.attention_norm.back_propagate(residual_error);
 
-        // Backpropagate the error through the `multi_headed` layer
-        let multi_headed_error = self.params.multi_headed.back_propagate(norm_error2.1);
+                // Backpropagate the error through the `attention_dropout` and `multi_headed` layers
+                let dropout_error = self.attention_dropout.back_propagate(norm_error2.1);
+                let multi_headed_error = self.params.multi_headed.back_propagate(dropout_error);
 
-        // Combine the error from the previous `add_and_norm` layer and the `multi_headed` layer
-        let prev_error = &norm_error2.0 + &multi_headed_error;
+                // Combine the error from the `attention_norm` layer and the `multi_headed` layer
+                &norm_error2.0 + &multi_headed_error
+            }
+            NormPlacement::Pre => {
+                // The residual passes the error straight through, alongside the feed-forward branch
+                let dropout_error = self.feed_forward_dropout.back_propagate(error.clone());
+                let feed_error = self.feed_forward_error(dropout_error);
+                let residual_error = error + self.feed_forward_norm.norm.back_propagate(feed_error);
 
-        // Return the previous error as the final output
-        prev_error
+                // Repeat for the multi-headed branch
+                let dropout_error = self.attention_dropout.back_propagate(residual_error.clone());
+                let multi_headed_error = self.params.multi_headed.back_propagate(dropout_error);
+                residual_error + self.attention_norm.norm.back_propagate(multi_headed_error)
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
+impl<F: BlockFloat> Parameters<F> for EncoderBlock<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        let mut params = self.params.multi_headed.parameters();
+        params.extend(self.params.feed_forward.parameters());
+        params.extend(self.attention_norm.parameters());
+        params.extend(self.feed_forward_norm.parameters());
+        params
+    }
+}
+
+impl<F: BlockFloat> Quantize for EncoderBlock<F> {
+    fn quantize(&mut self) {
+        self.params.multi_headed.quantize();
+        self.params.feed_forward.quantize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+    use rand::Rng;
+    use super::EncoderBlock;
+    use crate::activation::Activation;
+    use crate::block::tests::check_gradients;
+    use crate::config::{NormPlacement, TransformerConfig};
+    use crate::Float;
+
+    fn check_placement(norm_placement: NormPlacement) {
+        let config = TransformerConfig {
+            num_words: 3,
+            dimensionality: 4,
+            num_heads: 2,
+            hidden_layer_size: 6,
+            dropout_rate: 0.0,
+            feed_forward_activation: Activation::Gelu,
+            norm_placement,
+            ..TransformerConfig::default()
+        };
+        let mut rng = rand::thread_rng();
+        let input = Array2::from_shape_fn((config.sequence_length(), config.dimensionality), |_| rng.gen::<Float>() * 2.0 - 1.0);
+        check_gradients(&EncoderBlock::new(&config), &input, 2e-2);
+    }
+
+    #[test]
+    fn post_norm_gradients_match_finite_differences() {
+        check_placement(NormPlacement::Post);
+    }
+
+    #[test]
+    fn pre_norm_gradients_match_finite_differences() {
+        check_placement(NormPlacement::Pre);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::parameters::Parameters;
+use crate::transformer::Transformer;
+use crate::Float;
+use half::{bf16, f16};
+use log::info;
+use serde::{Serialize, Deserialize};
+
+/// The 16-bit float types a model can be stored in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HalfPrecision {
+    /// IEEE half precision, with more precision but a range of about ±65504
+    F16,
+    /// bfloat16, with the range of an f32 but only 8 bits of precision
+    Bf16,
+}
+
+impl HalfPrecision {
+    /// Parse the name of a precision given on the command line
+    pub fn from_name(name: &str) -> HalfPrecision {
+        match name {
+            "f16" => HalfPrecision::F16,
+            "bf16" => HalfPrecision::Bf16,
+            _ => panic!("Unknown precision {}, expected f16 or bf16", name),
+        }
+    }
+
+    /// The name of the precision, as used in file names
+    pub fn name(self) -> &'static str {
+        match self {
+            HalfPrecision::F16 => "f16",
+            HalfPrecision::Bf16 => "bf16",
+        }
+    }
+}
+
+/// A whole model, with its configuration, tokenizer and vocabulary, whose values are rounded to
+/// 16-bit floats. JSON stores each one as the integer of its bits, which takes about half the
+/// space of an f32. The model is converted back to `Float` to be run, so it only saves space on disk.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum HalfTransformer {
+    F16(Transformer<f16>),
+    Bf16(Transformer<bf16>),
+}
+
+impl HalfTransformer {
+    /// Round every value of a model to the given precision
+    pub fn new(transformer: &Transformer, precision: HalfPrecision) -> HalfTransformer {
+        match precision {
+            HalfPrecision::F16 => HalfTransformer::F16(transformer.cast()),
+            HalfPrecision::Bf16 => HalfTransformer::Bf16(transformer.cast()),
+        }
+    }
+
+    /// The precision the model is stored in
+    pub fn precision(&self) -> HalfPrecision {
+        match self {
+            HalfTransformer::F16(_) => HalfPrecision::F16,
+            HalfTransformer::Bf16(_) => HalfPrecision::Bf16,
+        }
+    }
+
+    /// Convert the model back to `Float`, so it can be run or trained
+    pub fn to_transformer(&self) -> Transformer {
+        match self {
+            HalfTransformer::F16(transformer) => transformer.cast(),
+            HalfTransformer::Bf16(transformer) => transformer.cast(),
+        }
+    }
+
+    /// Save the model to a file, which `Transformer::load` reads
+    pub fn save(&self, file_name: &str) {
+        let file = std::fs::File::create(file_name).expect("Unable to create file");
+        serde_json::to_writer(file, self).expect("Unable to write");
+    }
+}
+
+/// Whether the JSON of a model was written by `HalfTransformer`, which starts with the name of its precision
+pub(crate) fn is_half_model(json: &[u8]) -> bool {
+    json.starts_with(b"{\"F16\":") || json.starts_with(b"{\"Bf16\":")
+}
+
+/// Save a copy of a model with 16-bit values alongside the original, with a suffix of the
+/// precision, and report how much smaller it is and how much the parameters were rounded
+pub fn run_half(model_file_name: &str, precision_name: &str) {
+    let precision = HalfPrecision::from_name(precision_name);
+    let mut transformer = Transformer::load(model_file_name);
+    let half = HalfTransformer::new(&transformer, precision);
+
+    let half_file_name = format!("{}_{}.json", model_file_name.trim_end_matches(".json"), precision.name());
+    half.save(&half_file_name);
+    info!("Saved {} model to {}", precision.name(), half_file_name);
+
+    let original_size = std::fs::metadata(model_file_name).unwrap().len();
+    let half_size = std::fs::metadata(&half_file_name).unwrap().len();
+    info!("File size: {} bytes, down from {} bytes ({:.2} of the original)", half_size, original_size, half_size as Float / original_size as Float);
+
+    let mut rounded = half.to_transformer();
+    let max_error = transformer.parameters().iter().zip(rounded.parameters().iter()).flat_map(|(original, rounded)| {
+        original.iter().zip(rounded.iter()).map(|(x, y)| (x - y).abs() / x.abs().max(Float::MIN_POSITIVE)).collect::<Vec<Float>>()
+    }).fold(0.0, Float::max);
+    info!("Largest relative rounding error of a parameter: {:.6}", max_error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_half_model, HalfPrecision, HalfTransformer};
+    use crate::block::Block;
+    use crate::config::TransformerConfig;
+    use crate::parameters::Parameters;
+    use crate::transformer::tests::small_transformer;
+    use crate::transformer::Transformer;
+    use crate::Float;
+
+    /// Save a model at the given precision, load it from the file, and check every parameter and
+    /// prediction is within the rounding error of the format
+    fn check_round_trip(precision: HalfPrecision, relative_error: Float) {
+        let config = TransformerConfig { num_words: 4, dimensionality: 8, hidden_layer_size: 16, ..TransformerConfig::default() };
+        let mut transformer = small_transformer(config);
+        let file_name = std::env::temp_dir().join(format!("rusttransformer_{}_{}.json", precision.name(), std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+
+        let half = HalfTransformer::new(&transformer, precision);
+        assert_eq!(half.precision(), precision);
+        half.save(file_name);
+        let json = std::fs::read(file_name).unwrap();
+        assert!(is_half_model(&json));
+        let mut loaded = Transformer::load(file_name);
+        std::fs::remove_file(file_name).unwrap();
+
+        // Values below the smallest normal f16 lose relative precision, so allow a tiny absolute error too
+        for (original, loaded) in transformer.parameters().iter().zip(loaded.parameters().iter()) {
+            for (x, y) in original.iter().zip(loaded.iter()) {
+                assert!((x - y).abs() <= relative_error * x.abs() + 1e-7, "{} was loaded as {}", x, y);
+            }
+        }
+
+        // The configuration, tokenizer and vocabulary are saved with the weights
+        assert_eq!(loaded.config().dimensionality, 8);
+        let input = transformer.message_input("the cat sat on a mat", &[]);
+        assert_eq!(loaded.message_input("the cat sat on a mat", &[]).words, input.words);
+        let (expected, actual) = (transformer.infer(input.clone()), loaded.infer(input));
+        assert!(expected.iter().zip(actual.iter()).all(|(a, b)| (a - b).abs() < 0.05), "{} became {}", expected, actual);
+    }
+
+    #[test]
+    fn f16_round_trip() {
+        check_round_trip(HalfPrecision::F16, 1.0 / 2048.0);
+    }
+
+    #[test]
+    fn bf16_round_trip() {
+        check_round_trip(HalfPrecision::Bf16, 1.0 / 256.0);
+    }
+
+    #[test]
+    fn half_models_are_smaller() {
+        let config = TransformerConfig { num_words: 4, dimensionality: 8, hidden_layer_size: 16, ..TransformerConfig::default() };
+        let transformer = small_transformer(config);
+        let full = serde_json::to_vec(&transformer).unwrap();
+        let half = serde_json::to_vec(&HalfTransformer::new(&transformer, HalfPrecision::Bf16)).unwrap();
+        assert!(!is_half_model(&full));
+        assert!(half.len() * 5 < full.len() * 3, "The 16-bit model takes {} bytes, and the original {}", half.len(), full.len());
+    }
+}
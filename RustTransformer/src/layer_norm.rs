@@ -0,0 +1,179 @@
+use ndarray::{Axis, Array1, Array2, ArrayViewMutD};
+use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use crate::LR;
+use serde::{Serialize, Deserialize};
+
+// Defines struct for storing the learnable gain and bias of a layer norm
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LayerNormParams<F = Float> {
+    gain: Array1::<F>,
+    bias: Array1::<F>,
+}
+
+// Defines a layer normalisation struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LayerNorm<F = Float> {
+    input: Array2::<F>,
+    normalised: Array2::<F>,
+    epsilon: F,
+    params: LayerNormParams<F>,
+}
+
+impl<F: FloatStorage> LayerNorm<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> LayerNorm<G> {
+        LayerNorm {
+            input: self.input.mapv(cast),
+            normalised: self.normalised.mapv(cast),
+            epsilon: cast(self.epsilon),
+            params: LayerNormParams { gain: self.params.gain.mapv(cast), bias: self.params.bias.mapv(cast) },
+        }
+    }
+}
+
+impl<F: BlockFloat> LayerNorm<F> {
+    /// Create a new layer norm block with the given parameters
+    pub fn new(rows: usize, cols: usize, epsilon: Float) -> LayerNorm<F> {
+        // Start as the identity transform: a gain of one and a bias of zero
+        let gain = Array1::<F>::ones(cols);
+        let bias = Array1::<F>::zeros(cols);
+
+        let params = LayerNormParams { gain, bias };
+
+        let block: LayerNorm<F> = LayerNorm {
+            input: Array2::<F>::zeros((rows, cols)),
+            normalised: Array2::<F>::zeros((rows, cols)),
+            epsilon: F::constant(epsilon),
+            params,
+        };
+
+        block
+    }
+
+    /// Normalise each row of the input to a mean of zero and a variance of one
+    fn normalise(&self, mut value: Array2<F>) -> Array2<F> {
+        // Iterate over each row (axis 0) of the input matrix
+        for mut x in value.axis_iter_mut(Axis(0)) {
+            let mean = x.mean().unwrap();
+            // Epsilon keeps constant rows, such as padding, from dividing by zero
+            let stdev = (x.var(F::zero()) + self.epsilon).sqrt();
+
+            // Normalize each element in the row using mean and standard deviation
+            x.mapv_inplace(|y| (y - mean) / stdev);
+        }
+
+        value
+    }
+}
+
+impl<F: BlockFloat> Block for LayerNorm<F> {
+    type Input = Array2<F>;
+    type Output = Array2<F>;
+
+    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        self.input = value;
+        self.normalised = self.normalise(self.input.clone());
+
+        // Scale and shift the normalised rows by the learned gain and bias
+        &self.normalised * &self.params.gain + &self.params.bias
+    }
+
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        self.normalise(value) * &self.params.gain + &self.params.bias
+    }
+
+    fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        let lr = F::constant(LR);
+
+        // Error with respect to the normalised values before the gain is applied
+        let norm_error = &error * &self.params.gain;
+
+        // Update the gain and bias, summing their gradients over every row
+        let gain_rate = (&error * &self.normalised).sum_axis(Axis(0));
+        let bias_rate = error.sum_axis(Axis(0));
+        self.params.gain.scaled_add(-lr, &gain_rate);
+        self.params.bias.scaled_add(-lr, &bias_rate);
+
+        // Each input element in the word vector affects the output in multiple
+        // ways as it's used in the stdev and mean calcs. Multiplying the error by
+        // the Jacobean of each row simplifies to
+        // dC / dx = (g - mean(g) - x̂ * mean(g * x̂)) / stdev
+        // where g is the error of the normalised values x̂, so no n x n matrices are needed.
+        let stdev = (self.input.var_axis(Axis(1), F::zero()) + self.epsilon).mapv(F::sqrt).insert_axis(Axis(1));
+        let mean_error = norm_error.mean_axis(Axis(1)).unwrap().insert_axis(Axis(1));
+        let mean_scaled_error = (&norm_error * &self.normalised).mean_axis(Axis(1)).unwrap().insert_axis(Axis(1));
+        (norm_error - mean_error - &self.normalised * &mean_scaled_error) / stdev
+    }
+}
+
+impl<F: BlockFloat> Parameters<F> for LayerNorm<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        vec![self.params.gain.view_mut().into_dyn(), self.params.bias.view_mut().into_dyn()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{Array1, Array2, Axis};
+    use rand::Rng;
+    use super::LayerNorm;
+    use crate::block::Block;
+    use crate::block::tests::check_gradients;
+    use crate::Float;
+
+    /// The input error found by multiplying the error of each row by its full n x n Jacobian,
+    /// which the closed form in `back_propagate` replaced
+    fn jacobian_input_error(layer_norm: &LayerNorm, error: &Array2<Float>) -> Array2<Float> {
+        let norm_error = error * &layer_norm.params.gain;
+        let mut prev_error = Array2::<Float>::zeros(error.raw_dim());
+
+        for (count, x) in layer_norm.input.axis_iter(Axis(0)).enumerate() {
+            let n = x.len() as Float;
+            let i = Array2::<Float>::eye(n as usize);
+            let mean = x.mean().unwrap();
+            let stdev = (x.var(0.0) + layer_norm.epsilon).sqrt();
+            // Make a matrix from (xi-μ) * (xj-μ) for use in the jacobean
+            let x_matrix = Array2::from_shape_fn((n as usize, n as usize), |(i, j)| (x[i] - mean) * (x[j] - mean));
+            let jacobean = ((i * n) - 1.0) / (n * stdev) - (x_matrix / (n * stdev.powi(3)));
+            // Calculate all the dC / dx for each input x. There will be n rates of change per input element.
+            let p = Array2::from_shape_fn((n as usize, n as usize), |(i, j)| norm_error[[count, i]] * jacobean[[i, j]]);
+            // Sum each rate of change for each input to get the final dC / dx.
+            prev_error.row_mut(count).assign(&p.sum_axis(Axis(0)));
+        }
+
+        prev_error
+    }
+
+    #[test]
+    fn closed_form_matches_the_jacobian() {
+        let mut rng = rand::thread_rng();
+        let (rows, cols) = (4, 16);
+        let mut layer_norm = LayerNorm::new(rows, cols, 1e-5);
+        layer_norm.params.gain = Array1::from_shape_fn(cols, |_| rng.gen::<Float>() + 0.5);
+
+        // The last row is constant, like padding, so its variance is zero and epsilon dominates
+        let mut input = Array2::from_shape_fn((rows, cols), |_| rng.gen::<Float>() * 4.0 - 2.0);
+        input.row_mut(rows - 1).fill(0.3);
+        let error = Array2::from_shape_fn((rows, cols), |_| rng.gen::<Float>() - 0.5);
+
+        layer_norm.forward_propagate(input);
+        let expected = jacobian_input_error(&layer_norm, &error);
+        let actual = layer_norm.back_propagate(error);
+
+        for ((index, a), e) in actual.indexed_iter().zip(expected.iter()) {
+            assert!((a - e).abs() <= 1e-3 * (1.0 + e.abs()), "input error {:?} is {}, but the Jacobian gives {}", index, a, e);
+        }
+    }
+
+    #[test]
+    fn f64_gradients_of_a_row_with_little_variance() {
+        // In f32 the rounding error of the finite differences swamps the gradient of a nearly
+        // constant row, but the same block in f64 can be checked tightly
+        let mut rng = rand::thread_rng();
+        let mut input = Array2::from_shape_fn((2, 8), |_| rng.gen::<f64>() * 4.0 - 2.0);
+        input.row_mut(1).mapv_inplace(|x| 0.3 + x * 1e-3);
+        check_gradients(&LayerNorm::<f64>::new(2, 8, 1e-5), &input, 1e-4);
+    }
+}
@@ -1,14 +1,114 @@
-pub const LR: f32 = 0.0005;
+use ndarray::NdFloat;
+use num_traits::{FromPrimitive, NumCast, ToPrimitive};
+
+/// The floating point type the model is trained with, and the default type of every block.
+/// Enabling the `f64` feature switches the whole model to double precision.
+///
+/// `f64` is a build-mode switch rather than an additive feature: it changes the types of the
+/// public API, so code written against `f32` doesn't compile with it enabled. Cargo unifies
+/// features, so it should only be enabled from the top-level build, never by a crate which
+/// depends on this one.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+pub const LR: Float = 0.0005;
+
+/// The float types the values of a block can be stored in. Every block has a `cast` method
+/// which converts it between them, such as to store a model in a 16-bit type.
+pub trait FloatStorage: Copy + Send + Sync + 'static {
+    /// Convert the value to an f64, which holds every storage type exactly
+    fn widen(self) -> f64;
+    /// Round an f64 to the nearest value of this type
+    fn narrow(x: f64) -> Self;
+}
+
+/// Convert a value from one float type to another
+pub fn cast<F: FloatStorage, G: FloatStorage>(x: F) -> G {
+    G::narrow(x.widen())
+}
+
+/// The float types blocks can compute with. Every block is generic over it, so a block can run
+/// in f64 to check its gradients or debug numerical issues while the rest of the model uses `Float`.
+pub trait BlockFloat: NdFloat + FromPrimitive + FloatStorage {
+    /// Convert a constant, such as a hyperparameter or a count, to this type
+    fn constant<T: ToPrimitive>(x: T) -> Self {
+        <Self as NumCast>::from(x).expect("The constant doesn't fit in the float type")
+    }
+}
+
+impl FloatStorage for f32 {
+    fn widen(self) -> f64 {
+        self.into()
+    }
+
+    fn narrow(x: f64) -> f32 {
+        x as f32
+    }
+}
+
+impl FloatStorage for f64 {
+    fn widen(self) -> f64 {
+        self
+    }
+
+    fn narrow(x: f64) -> f64 {
+        x
+    }
+}
+
+#[cfg(feature = "half")]
+impl FloatStorage for half::f16 {
+    fn widen(self) -> f64 {
+        self.to_f64()
+    }
+
+    fn narrow(x: f64) -> half::f16 {
+        half::f16::from_f64(x)
+    }
+}
+
+#[cfg(feature = "half")]
+impl FloatStorage for half::bf16 {
+    fn widen(self) -> f64 {
+        self.to_f64()
+    }
+
+    fn narrow(x: f64) -> half::bf16 {
+        half::bf16::from_f64(x)
+    }
+}
+
+impl BlockFloat for f32 {}
+impl BlockFloat for f64 {}
+
+// Link the BLAS library ndarray hands its matrix products to
+#[cfg(feature = "blas")]
+extern crate blas_src;
 
 pub mod run;
+pub mod config;
 pub mod logger;
 pub mod dataset;
 pub mod block;
+pub mod parameters;
+pub mod activation;
 pub mod self_attention;
 pub mod embedding;
+pub mod char_encoder;
 pub mod dense;
+pub mod dropout;
 pub mod multi_headed_attention;
+pub mod layer_norm;
 pub mod add_and_norm;
 pub mod encoder_block;
 pub mod positional_encoder;
-pub mod transformer;
\ No newline at end of file
+pub mod segment_embedding;
+pub mod transformer;
+pub mod profile;
+pub mod open_set;
+pub mod siamese;
+pub mod quantize;
+#[cfg(feature = "half")]
+pub mod half_precision;
\ No newline at end of file
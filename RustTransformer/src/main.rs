@@ -1,4 +1,5 @@
 use rusttransformer::*;
+use chat_core::tokenizer::Tokenizer;
 use log::{LevelFilter, info};
 // use std::io;
 
@@ -7,6 +8,58 @@ fn main() {
     log::set_logger(&logger::CustomLogger).unwrap();
     log::set_max_level(LevelFilter::Info);
 
+    // Profile the author of a file of messages with a saved model:
+    // cargo run --release -- profile <model file> <messages file>
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 4 && args[1] == "profile" {
+        profile::run_profile(&args[2], &args[3]);
+        return;
+    }
+
+    // Measure false-accept and false-reject rates of open-set verification with a saved model,
+    // and save the calibrated open-set classifier next to it:
+    // cargo run --release -- open-set <model file> <known authors' messages> <unknown authors' messages>
+    if args.len() == 5 && args[1] == "open-set" {
+        open_set::run_open_set(&args[2], &args[3], &args[4]);
+        return;
+    }
+
+    // Predict the author of each message, or an unknown author, with a model and its calibrated open-set classifier:
+    // cargo run --release -- open-set-predict <model file> <messages file>
+    if args.len() == 4 && args[1] == "open-set-predict" {
+        open_set::run_open_set_predict(&args[2], &args[3]);
+        return;
+    }
+
+    // Compare every pair of messages in a file by the cosine similarity of their embeddings from a saved Siamese model:
+    // cargo run --release -- siamese-compare <model file> <messages file>
+    if args.len() == 4 && args[1] == "siamese-compare" {
+        siamese::run_compare(&args[2], &args[3]);
+        return;
+    }
+
+    // Quantize a saved model to int8 and compare its test accuracy with the float model:
+    // cargo run --release -- quantize <model file>
+    if args.len() == 3 && args[1] == "quantize" {
+        quantize::run_quantize(&args[2]);
+        return;
+    }
+
+    // Save a copy of a model in 16-bit floats, which every command can load:
+    // cargo run --release --features half -- half <model file> <f16|bf16>
+    #[cfg(feature = "half")]
+    if args.len() == 4 && args[1] == "half" {
+        half_precision::run_half(&args[2], &args[3]);
+        return;
+    }
+
+    // Measure how much a saved model relies on each stylometric feature:
+    // cargo run --release -- feature-importance <model file>
+    if args.len() == 3 && args[1] == "feature-importance" {
+        run::run_feature_importance(&args[2]);
+        return;
+    }
+
     // println!("Enter the max number of words: ");
     // let mut input = String::new();
     // io::stdin().read_line(&mut input).expect("Failed to read input.");
@@ -32,18 +85,56 @@ fn main() {
     // io::stdin().read_line(&mut input).expect("Failed to read input.");
     // let hidden_layer_size = input.trim().parse().expect("Invalid input.");
 
-    let num_words = 10;
-    let dimensionality = 64;
-    let num_encoders = 1;
-    let num_heads = 1;
-    let hidden_layer_size = 100;
+    let config = config::TransformerConfig {
+        num_words: 10,
+        dimensionality: 64,
+        num_encoders: 1,
+        num_heads: 1,
+        hidden_layer_size: 100,
+        layer_norm_epsilon: 1e-5,
+        norm_placement: config::NormPlacement::Post,
+        dropout_rate: 0.1,
+        feed_forward_activation: activation::Activation::Relu,
+        positional_encoding: positional_encoder::PositionalEncoding::Sinusoidal,
+        embedding_mode: embedding::EmbeddingMode::Frozen,
+        unknown_vector: embedding::UnknownVector::Learned,
+        ngram_fallback: false,
+        char_encoder: None,
+        stylometric_features: false,
+        context_turns: 0,
+    };
     let num_messages = 66000;
-    info!("num_words: {}", num_words);
-    info!("dimensionality: {}", dimensionality);
-    info!("num_encoders: {}", num_encoders);
-    info!("num_heads: {}", num_heads);
-    info!("hidden_layer_size: {}", hidden_layer_size);
+    // Number of threads training replicas of the model in parallel. With more than one, every
+    // batch of 10 examples per thread is one step with the mean update of the batch.
+    let num_threads = 1;
+    info!("num_words: {}", config.num_words);
+    info!("dimensionality: {}", config.dimensionality);
+    info!("num_encoders: {}", config.num_encoders);
+    info!("num_heads: {}", config.num_heads);
+    info!("hidden_layer_size: {}", config.hidden_layer_size);
+    info!("layer_norm_epsilon: {}", config.layer_norm_epsilon);
+    info!("norm_placement: {:?}", config.norm_placement);
+    info!("dropout_rate: {}", config.dropout_rate);
+    info!("feed_forward_activation: {:?}", config.feed_forward_activation);
+    info!("positional_encoding: {:?}", config.positional_encoding);
+    info!("embedding_mode: {:?}", config.embedding_mode);
+    info!("unknown_vector: {:?}", config.unknown_vector);
+    info!("ngram_fallback: {}", config.ngram_fallback);
+    info!("char_encoder: {:?}", config.char_encoder);
+    info!("stylometric_features: {}", config.stylometric_features);
+    info!("context_turns: {}", config.context_turns);
     info!("num_messages: {}", num_messages);
+    info!("num_threads: {}", num_threads);
+
+    // Split messages into lower-cased words, or load a tokenizer created by WordEmbeddings
+    let tokenizer = Tokenizer::default();
+    // let tokenizer = Tokenizer::load("../tokenizer.json");
 
-    run::run(num_words, dimensionality, num_encoders, num_heads, hidden_layer_size, num_messages);
+    // Train a Siamese encoder for same-author verification instead of the classifier:
+    // cargo run --release -- siamese
+    if args.len() == 2 && args[1] == "siamese" {
+        siamese::run(config, tokenizer, num_messages);
+    } else {
+        run::run(config, tokenizer, num_messages, num_threads);
+    }
 }
\ No newline at end of file
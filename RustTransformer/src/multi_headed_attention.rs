@@ -1,36 +1,57 @@
-use ndarray::{arr1, Array1, Array2, Axis};
+use ndarray::{arr1, concatenate, Array1, Array2, ArrayViewMutD, Axis};
 use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::quantize::Quantize;
 use crate::self_attention::SelfAttention;
+use crate::activation::Activation;
 use crate::dense::Dense;
+use crate::positional_encoder::{alibi_slope, PositionalEncoding};
+use crate::{cast, BlockFloat, Float, FloatStorage};
 use serde::{Serialize, Deserialize};
 
 // Defines attention heads and dense layer.
-#[derive(Serialize, Deserialize)]
-pub struct MultiHeadedAttentionParams {
-    heads: Array1::<SelfAttention>,
-    linear: Dense,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MultiHeadedAttentionParams<F = Float> {
+    heads: Array1::<SelfAttention<F>>,
+    linear: Dense<F>,
 }
 
 // Defines multi-headed attention struct
-#[derive(Serialize, Deserialize)]
-pub struct MultiHeadedAttention {
-    input: Array2::<f32>,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MultiHeadedAttention<F = Float> {
+    input: Array2::<F>,
     rows: usize,
     cols: usize,
     num_heads: usize,
-    params: MultiHeadedAttentionParams,
+    params: MultiHeadedAttentionParams<F>,
 }
 
-impl MultiHeadedAttention {
+impl<F: FloatStorage> MultiHeadedAttention<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> MultiHeadedAttention<G> {
+        MultiHeadedAttention {
+            input: self.input.mapv(cast),
+            rows: self.rows,
+            cols: self.cols,
+            num_heads: self.num_heads,
+            params: MultiHeadedAttentionParams {
+                heads: self.params.heads.iter().map(SelfAttention::cast).collect(),
+                linear: self.params.linear.cast(),
+            },
+        }
+    }
+}
+
+impl<F: BlockFloat> MultiHeadedAttention<F> {
     /// Create a new self-attention block with the given parameters
-    pub fn new(num_heads: usize, rows: usize, cols: usize) -> MultiHeadedAttention {
-        let heads: Array1<SelfAttention> = Array1::from_shape_fn(num_heads, |_| SelfAttention::new(rows, cols));
-        let linear: Dense = Dense::new(arr1(&[rows*cols*num_heads, rows*cols]), true, false);
+    pub fn new(num_heads: usize, rows: usize, cols: usize, positional_encoding: PositionalEncoding) -> MultiHeadedAttention<F> {
+        let heads: Array1<SelfAttention<F>> = Array1::from_shape_fn(num_heads, |i| SelfAttention::new(rows, cols, positional_encoding, alibi_slope(i, num_heads)));
+        let linear: Dense<F> = Dense::new(arr1(&[rows*cols*num_heads, rows*cols]), Activation::Identity);
 
         let params = MultiHeadedAttentionParams { heads, linear };
 
-        let block: MultiHeadedAttention = MultiHeadedAttention {
-            input: Array2::<f32>::zeros((rows, cols)),
+        let block: MultiHeadedAttention<F> = MultiHeadedAttention {
+            input: Array2::<F>::zeros((rows, cols)),
             rows,
             cols,
             num_heads,
@@ -41,15 +62,15 @@ impl MultiHeadedAttention {
     }
 }
 
-impl Block for MultiHeadedAttention {
-    type Input = Array2<f32>;
-    type Output = Array2<f32>;
+impl<F: BlockFloat> Block for MultiHeadedAttention<F> {
+    type Input = Array2<F>;
+    type Output = Array2<F>;
 
     fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
         self.input = value;
 
         // Initialize an array to store the concatenated outputs from different heads
-        let mut concat_heads = Array1::<f32>::zeros(self.params.linear.input_size);
+        let mut concat_heads = Array1::<F>::zeros(self.params.linear.input_size);
     
         // Variable to keep track of the current index in the concatenated heads array
         let mut concat_index = 0;
@@ -75,6 +96,16 @@ impl Block for MultiHeadedAttention {
         output.into_shape([self.input.shape()[0], self.input.shape()[1]]).unwrap()
     }
 
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        // Concatenate the flattened outputs of every head, in the same order as during training
+        let heads: Vec<Array1<F>> = self.params.heads.iter().map(|head| head.infer(value.clone()).into_shape(self.rows*self.cols).unwrap()).collect();
+        let views: Vec<_> = heads.iter().map(|head| head.view()).collect();
+        let concat_heads = concatenate(Axis(0), &views).unwrap();
+
+        let output = self.params.linear.infer(concat_heads);
+        output.into_shape([self.rows, self.cols]).unwrap()
+    }
+
     fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
         // Flatten the error tensor into a 1D array
         let flat_error = error.into_shape(self.rows*self.cols).unwrap();
@@ -83,7 +114,7 @@ impl Block for MultiHeadedAttention {
         let linear_error = self.params.linear.back_propagate(flat_error);
 
         // Initialize an empty array to store the accumulated error from all heads
-        let mut prev_error = Array2::<f32>::zeros((self.rows,self.cols));
+        let mut prev_error = Array2::<F>::zeros((self.rows,self.cols));
 
         // Reshape the linear error into a multi-headed error tensor
         let multi_headed_error = linear_error.into_shape([self.num_heads,self.rows,self.cols]).unwrap();
@@ -103,4 +134,21 @@ impl Block for MultiHeadedAttention {
         // Return the accumulated previous error
         prev_error
     }
-}
\ No newline at end of file
+}
+
+impl<F: BlockFloat> Parameters<F> for MultiHeadedAttention<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        let mut params: Vec<ArrayViewMutD<F>> = self.params.heads.iter_mut().flat_map(|head| head.parameters()).collect();
+        params.extend(self.params.linear.parameters());
+        params
+    }
+}
+
+impl<F: BlockFloat> Quantize for MultiHeadedAttention<F> {
+    fn quantize(&mut self) {
+        for head in self.params.heads.iter_mut() {
+            head.quantize();
+        }
+        self.params.linear.quantize();
+    }
+}
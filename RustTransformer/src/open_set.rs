@@ -0,0 +1,254 @@
+use ndarray::Array1;
+use crate::dataset::AUTHOR_NAMES;
+use crate::dense::softmax;
+use crate::profile::load_messages;
+use crate::transformer::Transformer;
+use crate::Float;
+use serde::{Serialize, Deserialize};
+use log::info;
+use rayon::prelude::*;
+
+/// Scores measuring how likely a message is to come from one of the known authors
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OodScore {
+    /// The largest softmax probability
+    MaxSoftmax,
+    /// The negative free energy, the log-sum-exp of the logits
+    Energy,
+}
+
+impl OodScore {
+    /// Score the logits of a message. Higher scores are more likely to come from a known author.
+    pub fn score(&self, logits: &Array1<Float>) -> Float {
+        match self {
+            OodScore::MaxSoftmax => softmax(logits.clone()).fold(0.0, |a, &b| a.max(b)),
+            OodScore::Energy => {
+                let max = logits.fold(Float::MIN, |a, &b| a.max(b));
+                max + logits.mapv(|x| (x - max).exp()).sum().ln()
+            }
+        }
+    }
+}
+
+/// An open-set classifier, which rejects messages scoring below the threshold as coming from an unknown author
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct OpenSet {
+    pub score: OodScore,
+    pub threshold: Float,
+}
+
+impl OpenSet {
+    /// Predict the author of a message, or None if it comes from none of the known authors
+    pub fn predict(&self, transformer: &Transformer, msg: &str) -> Option<usize> {
+        let logits = transformer.predict_logits(msg);
+        if self.score.score(&logits) < self.threshold {
+            return None;
+        }
+
+        // The softmax keeps the order of the logits, so the most likely author has the highest logit
+        let mut max_index = 0;
+        for i in 1..logits.len() {
+            if logits[i] > logits[max_index] {
+                max_index = i;
+            }
+        }
+        Some(max_index)
+    }
+
+    /// Load a calibrated open-set classifier from a file
+    pub fn load(file_name: &str) -> OpenSet {
+        let file = std::fs::File::open(file_name).expect("Failed to open open-set file");
+        serde_json::from_reader(std::io::BufReader::new(file)).expect("Failed to read open-set file")
+    }
+
+    /// Save the open-set classifier to a file
+    pub fn save(&self, file_name: &str) {
+        let file = std::fs::File::create(file_name).expect("Unable to create file");
+        serde_json::to_writer(file, self).expect("Unable to write");
+    }
+}
+
+/// The open-set classifier calibrated for a model is saved alongside it with an `_open_set` suffix
+pub fn open_set_file_name(model_file_name: &str) -> String {
+    format!("{}_open_set.json", model_file_name.trim_end_matches(".json"))
+}
+
+/// How often an open-set classifier makes each kind of mistake at a threshold
+#[derive(Clone, Copy, Debug)]
+pub struct VerificationRates {
+    pub threshold: Float,
+    /// The fraction of messages from unknown authors which are accepted
+    pub false_accept_rate: Float,
+    /// The fraction of messages from known authors which are rejected
+    pub false_reject_rate: Float,
+}
+
+/// Measure the error rates of a threshold on the scores of known and unknown messages.
+/// Both sets of scores must be non-empty, as each rate is a fraction of one of them.
+pub fn error_rates(known_scores: &[Float], unknown_scores: &[Float], threshold: Float) -> VerificationRates {
+    assert!(!known_scores.is_empty(), "Error rates need at least one message from a known author");
+    assert!(!unknown_scores.is_empty(), "Error rates need at least one message from an unknown author");
+    let rejected = known_scores.iter().filter(|&&score| score < threshold).count();
+    let accepted = unknown_scores.iter().filter(|&&score| score >= threshold).count();
+
+    VerificationRates {
+        threshold,
+        false_accept_rate: accepted as Float / unknown_scores.len() as Float,
+        false_reject_rate: rejected as Float / known_scores.len() as Float,
+    }
+}
+
+/// The threshold which rejects roughly `false_reject_rate` of the known messages
+pub fn calibrate(known_scores: &[Float], false_reject_rate: Float) -> Float {
+    assert!(!known_scores.is_empty(), "Calibrating a threshold needs at least one message from a known author");
+    let mut sorted = known_scores.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let index = ((sorted.len() as Float * false_reject_rate) as usize).min(sorted.len() - 1);
+    sorted[index]
+}
+
+/// The error rates at the threshold where false accepts and false rejects are closest to equal
+pub fn equal_error_rate(known_scores: &[Float], unknown_scores: &[Float]) -> VerificationRates {
+    known_scores.iter().chain(unknown_scores.iter())
+        .map(|&threshold| error_rates(known_scores, unknown_scores, threshold))
+        .min_by(|a, b| (a.false_accept_rate - a.false_reject_rate).abs().total_cmp(&(b.false_accept_rate - b.false_reject_rate).abs()))
+        .unwrap()
+}
+
+/// The fraction of known messages the saved open-set classifier rejects
+const TARGET_FALSE_REJECT_RATE: Float = 0.05;
+
+/// Evaluate open-set verification with a saved model, using messages from authors it was
+/// trained on and messages from authors it has never seen. The score which accepts the fewest
+/// unknown messages at the target false-reject rate is saved alongside the model.
+pub fn run_open_set(model_file_name: &str, known_file: &str, unknown_file: &str) {
+    let transformer = Transformer::load(model_file_name);
+
+    // Score the messages in parallel
+    let logits = |messages: Vec<String>| -> Vec<Array1<Float>> {
+        messages.par_iter().filter(|msg| !msg.trim().is_empty()).map(|msg| transformer.predict_logits(msg)).collect()
+    };
+    let known_logits = logits(load_messages(known_file));
+    let unknown_logits = logits(load_messages(unknown_file));
+    info!("Evaluating {} known and {} unknown messages", known_logits.len(), unknown_logits.len());
+
+    let mut best: Option<(OpenSet, Float)> = None;
+    for score in [OodScore::MaxSoftmax, OodScore::Energy] {
+        let known_scores: Vec<Float> = known_logits.iter().map(|logits| score.score(logits)).collect();
+        let unknown_scores: Vec<Float> = unknown_logits.iter().map(|logits| score.score(logits)).collect();
+
+        let calibrated = error_rates(&known_scores, &unknown_scores, calibrate(&known_scores, TARGET_FALSE_REJECT_RATE));
+        let equal = equal_error_rate(&known_scores, &unknown_scores);
+        info!("{:?} at {}% target FRR: threshold {:.4}, FAR {:.4}, FRR {:.4}", score, TARGET_FALSE_REJECT_RATE * 100.0, calibrated.threshold, calibrated.false_accept_rate, calibrated.false_reject_rate);
+        info!("{:?} at equal error rate: threshold {:.4}, FAR {:.4}, FRR {:.4}", score, equal.threshold, equal.false_accept_rate, equal.false_reject_rate);
+
+        if best.is_none_or(|(_, false_accept_rate)| calibrated.false_accept_rate < false_accept_rate) {
+            best = Some((OpenSet { score, threshold: calibrated.threshold }, calibrated.false_accept_rate));
+        }
+    }
+
+    let (open_set, _) = best.unwrap();
+    let open_set_file = open_set_file_name(model_file_name);
+    open_set.save(&open_set_file);
+    info!("Saved {:?} open-set classifier to {}", open_set.score, open_set_file);
+}
+
+/// Predict the author of each message in a file with a saved model and the open-set classifier
+/// calibrated for it, logging messages from none of the known authors as an unknown author
+pub fn run_open_set_predict(model_file_name: &str, messages_file: &str) {
+    let transformer = Transformer::load(model_file_name);
+    let open_set = OpenSet::load(&open_set_file_name(model_file_name));
+
+    let messages: Vec<String> = load_messages(messages_file).into_iter().filter(|msg| !msg.trim().is_empty()).collect();
+    let predictions: Vec<Option<usize>> = messages.par_iter().map(|msg| open_set.predict(&transformer, msg)).collect();
+    for (msg, prediction) in messages.iter().zip(predictions) {
+        let author = prediction.map_or("unknown author", |author| AUTHOR_NAMES[author]);
+        info!("{}: {}", author, msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{calibrate, equal_error_rate, error_rates, OodScore, OpenSet};
+    use crate::config::TransformerConfig;
+    use crate::run::predicted_author;
+    use crate::transformer::tests::small_transformer;
+    use crate::Float;
+
+    fn known() -> Vec<Float> {
+        vec![0.6, 0.1, 0.9, 0.4]
+    }
+
+    fn unknown() -> Vec<Float> {
+        vec![0.5, 0.2]
+    }
+
+    #[test]
+    fn error_rates_count_scores_on_each_side_of_the_threshold() {
+        // 0.1 and 0.4 are rejected, and 0.5 is accepted as its score equals the threshold
+        let rates = error_rates(&known(), &unknown(), 0.5);
+        assert_eq!(rates.false_reject_rate, 0.5);
+        assert_eq!(rates.false_accept_rate, 0.5);
+
+        let rates = error_rates(&known(), &unknown(), 0.05);
+        assert_eq!(rates.false_reject_rate, 0.0);
+        assert_eq!(rates.false_accept_rate, 1.0);
+    }
+
+    #[test]
+    fn calibrate_picks_the_score_at_the_target_rate() {
+        let known = known();
+        assert_eq!(calibrate(&known, 0.0), known[1]);
+        // The threshold 0.4 rejects only 0.1, a quarter of the known messages
+        assert_eq!(calibrate(&known, 0.25), known[3]);
+        assert_eq!(error_rates(&known, &unknown(), calibrate(&known, 0.25)).false_reject_rate, 0.25);
+        // Rates of one or more are clamped to the highest score
+        assert_eq!(calibrate(&known, 1.0), known[2]);
+    }
+
+    #[test]
+    fn equal_error_rate_balances_both_rates() {
+        let rates = equal_error_rate(&known(), &unknown());
+        assert_eq!(rates.threshold, unknown()[0]);
+        assert_eq!(rates.false_accept_rate, 0.5);
+        assert_eq!(rates.false_reject_rate, 0.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one message from a known author")]
+    fn calibrate_rejects_empty_scores() {
+        calibrate(&[], 0.05);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one message from an unknown author")]
+    fn error_rates_reject_empty_scores() {
+        error_rates(&known(), &[], 0.5);
+    }
+
+    #[test]
+    fn predict_rejects_scores_below_the_threshold() {
+        let transformer = small_transformer(TransformerConfig { num_words: 4, dimensionality: 8, hidden_layer_size: 16, ..TransformerConfig::default() });
+        let msg = "the cat sat";
+        let author = predicted_author(&transformer.predict(msg));
+
+        for score in [OodScore::MaxSoftmax, OodScore::Energy] {
+            let value = score.score(&transformer.predict_logits(msg));
+            assert_eq!(OpenSet { score, threshold: value }.predict(&transformer, msg), Some(author));
+            assert_eq!(OpenSet { score, threshold: value + 1e-3 }.predict(&transformer, msg), None);
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let file_name = std::env::temp_dir().join(format!("open_set_{}.json", std::process::id()));
+        let file_name = file_name.to_str().unwrap();
+        let open_set = OpenSet { score: OodScore::Energy, threshold: 1.25 };
+        open_set.save(file_name);
+        let loaded = OpenSet::load(file_name);
+        std::fs::remove_file(file_name).unwrap();
+
+        assert_eq!(loaded.score, open_set.score);
+        assert_eq!(loaded.threshold, open_set.threshold);
+    }
+}
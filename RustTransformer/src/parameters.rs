@@ -0,0 +1,80 @@
+use ndarray::{ArrayD, ArrayViewMutD};
+use crate::Float;
+
+/// A trait for a block with trainable parameters of the float type `F`
+pub trait Parameters<F = Float> {
+    /// Mutable views of every trainable parameter, always in the same order so
+    /// the parameters of replicas of a model line up with each other. Values which
+    /// are never updated, such as frozen word vectors, are left out.
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>>;
+}
+
+/// Copy the parameters of a model, so the threads training its replicas can all read them
+pub fn snapshot<T: Parameters>(model: &mut T) -> Vec<ArrayD<Float>> {
+    model.parameters().iter().map(|param| param.to_owned()).collect()
+}
+
+/// Add the update a replica has made since its parameters were `base` to `updates`, then
+/// reset the replica to `base`, so every update of a batch is computed from the same parameters
+pub fn take_update<T: Parameters>(replica: &mut T, base: &[ArrayD<Float>], updates: &mut [ArrayD<Float>]) {
+    for ((mut param, base), update) in replica.parameters().into_iter().zip(base).zip(updates.iter_mut()) {
+        *update += &param;
+        *update -= base;
+        param.assign(base);
+    }
+}
+
+/// Apply the mean of `count` updates, summed by each replica into one set of `updates`, to the
+/// model, then copy the updated parameters into every replica.
+/// The updates are averaged rather than summed, as in synchronous data-parallel SGD, so the
+/// size of each step doesn't grow with the size of the batch or the number of replicas.
+pub fn apply_mean_update<T: Parameters>(model: &mut T, replicas: &mut [T], updates: &[Vec<ArrayD<Float>>], count: usize) {
+    let scale = 1.0 / count as Float;
+    let mut model_params = model.parameters();
+    for (i, param) in model_params.iter_mut().enumerate() {
+        for replica_updates in updates {
+            param.scaled_add(scale, &replica_updates[i]);
+        }
+    }
+
+    for replica in replicas.iter_mut() {
+        for (mut replica_param, param) in replica.parameters().into_iter().zip(model_params.iter()) {
+            replica_param.assign(param);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_mean_update, snapshot, take_update, Parameters};
+    use crate::layer_norm::LayerNorm;
+
+    #[test]
+    fn replicas_take_the_mean_update_from_the_same_parameters() {
+        let mut model = LayerNorm::new(1, 3, 1e-5);
+        let mut replicas = vec![model.clone(), model.clone()];
+        let base = snapshot(&mut model);
+        let mut updates = vec![snapshot(&mut model), snapshot(&mut model)];
+        for replica_updates in updates.iter_mut() {
+            replica_updates.iter_mut().for_each(|update| update.fill(0.0));
+        }
+
+        // The gains start at one, so the first replica's updates are +2 and -1, and it is reset after each
+        replicas[0].parameters()[0].fill(3.0);
+        take_update(&mut replicas[0], &base, &mut updates[0]);
+        assert_eq!(replicas[0].parameters(), model.parameters());
+        replicas[0].parameters()[0].fill(0.0);
+        take_update(&mut replicas[0], &base, &mut updates[0]);
+        replicas[1].parameters()[0].fill(-1.0);
+        replicas[1].parameters()[1].fill(0.6);
+        take_update(&mut replicas[1], &base, &mut updates[1]);
+
+        // The mean of the three updates is (2 - 1 - 2) / 3 for the gains and 0.6 / 3 for the biases
+        apply_mean_update(&mut model, &mut replicas, &updates, 3);
+        assert!(model.parameters()[0].iter().all(|&x| (x - (1.0 - 1.0 / 3.0)).abs() < 1e-6));
+        assert!(model.parameters()[1].iter().all(|&x| (x - 0.2).abs() < 1e-6));
+        for replica in replicas.iter_mut() {
+            assert_eq!(replica.parameters(), model.parameters());
+        }
+    }
+}
@@ -1,55 +1,132 @@
-use ndarray::Array2;
+use ndarray::{Array1, Array2, ArrayViewMutD};
 use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use crate::LR;
+use rand_distr::{Distribution, Normal};
 use serde::{Serialize, Deserialize};
 
-// Defines an add and norm struct
-#[derive(Serialize, Deserialize)]
-pub struct PositionalEncoder {
-    input: Array2::<f32>,
-    dimensionality: usize,
+/// Strategies for giving the model information about word order
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionalEncoding {
+    /// Fixed sine and cosine encodings added to the embeddings
+    Sinusoidal,
+    /// Trainable absolute position embeddings added to the embeddings
+    Learned,
+    /// Rotary position embeddings applied to queries and keys inside self-attention
+    Rotary,
+    /// Linear attention biases which penalise distant words inside self-attention
+    Alibi,
 }
 
-impl PositionalEncoder {
-    /// Create a new add and norm block with the given parameters
-    pub fn new(rows: usize, cols: usize) -> PositionalEncoder {
+/// Rotate each pair of elements in `vector` by an angle proportional to `position`.
+/// Rotating by a negative position undoes the rotation.
+pub(crate) fn rotate<F: BlockFloat>(vector: &mut Array1<F>, position: F) {
+    let dimensionality = vector.len();
+    for k in 0..dimensionality / 2 {
+        let angle = position / F::constant(10000.0).powf(F::constant(2 * k) / F::constant(dimensionality));
+        let (sin, cos) = angle.sin_cos();
+        let (a, b) = (vector[2 * k], vector[2 * k + 1]);
+        vector[2 * k] = a * cos - b * sin;
+        vector[2 * k + 1] = a * sin + b * cos;
+    }
+}
 
-        let block: PositionalEncoder = PositionalEncoder {
-            input: Array2::<f32>::zeros((rows, cols)),
-            dimensionality: cols,
-        };
+/// The ALiBi slope for `head`, forming a geometric sequence across the heads
+pub(crate) fn alibi_slope(head: usize, num_heads: usize) -> Float {
+    Float::powf(2.0, -8.0 * (head + 1) as Float / num_heads as Float)
+}
 
-        block
-    }
+// Defines a positional encoder struct
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PositionalEncoder<F = Float> {
+    input: Array2::<F>,
+    encoding: PositionalEncoding,
+    positional_encodings: Array2::<F>,
 }
 
-impl Block for PositionalEncoder {
-    type Input = Array2<f32>;
-    type Output = Array2<f32>;
+impl<F: FloatStorage> PositionalEncoder<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> PositionalEncoder<G> {
+        PositionalEncoder {
+            input: self.input.mapv(cast),
+            encoding: self.encoding,
+            positional_encodings: self.positional_encodings.mapv(cast),
+        }
+    }
+}
 
-    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
-        self.input = value;  // Set the input value for the layer.
-    
+impl<F: BlockFloat> PositionalEncoder<F> {
+    /// Create a new positional encoder block with the given parameters
+    pub fn new(rows: usize, cols: usize, encoding: PositionalEncoding) -> PositionalEncoder<F> {
         // Create positional encodings matrix.
-        let mut positional_encodings = Array2::<f32>::zeros((self.input.shape()[0], self.dimensionality));
+        let mut positional_encodings = Array2::<F>::zeros((rows, cols));
 
-        // Iterate over rows of the input.
-        for i in 0..self.input.shape()[0] {
-            // Iterate over columns of the input.
-            for j in 0..self.dimensionality {
-                // Calculate the angle for positional encoding.
-                let angle = i as f32 / f32::powf(10000.0, 2.0 * j as f32 / self.dimensionality as f32);
+        match encoding {
+            PositionalEncoding::Sinusoidal => {
+                // Iterate over rows of the input.
+                for i in 0..rows {
+                    // Iterate over columns of the input.
+                    for j in 0..cols {
+                        // Calculate the angle for positional encoding.
+                        let angle = F::constant(i) / F::constant(10000.0).powf(F::constant(2 * j) / F::constant(cols));
 
-                // Compute sine or cosine based on the column index.
-                positional_encodings[[i,j]] = if j % 2 == 0 { angle.sin() } else { angle.cos() };
+                        // Compute sine or cosine based on the column index.
+                        positional_encodings[[i,j]] = if j % 2 == 0 { angle.sin() } else { angle.cos() };
+                    }
+                }
+            }
+            PositionalEncoding::Learned => {
+                // Start with small random embeddings so positions are distinguishable
+                let normal = Normal::new(0.0, 0.02).unwrap();
+                positional_encodings.mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
             }
+            // Relative encodings are applied inside self-attention instead
+            PositionalEncoding::Rotary | PositionalEncoding::Alibi => {}
         }
 
+        let block: PositionalEncoder<F> = PositionalEncoder {
+            input: Array2::<F>::zeros((rows, cols)),
+            encoding,
+            positional_encodings,
+        };
+
+        block
+    }
+}
+
+impl<F: BlockFloat> Block for PositionalEncoder<F> {
+    type Input = Array2<F>;
+    type Output = Array2<F>;
+
+    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        self.input = value;  // Set the input value for the layer.
+
         // Add positional encodings to the input.
-        let output = &positional_encodings + &self.input;
-        output  // Return the output.
+        &self.positional_encodings + &self.input
+    }
+
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        &self.positional_encodings + &value
     }
 
     fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        // Only learned encodings have trainable parameters
+        if self.encoding == PositionalEncoding::Learned {
+            self.positional_encodings.scaled_add(-F::constant(LR), &error);
+        }
+
         error  // Return the error for backpropagation.
     }
-}
\ No newline at end of file
+}
+
+impl<F: BlockFloat> Parameters<F> for PositionalEncoder<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        // Only learned encodings are trained
+        if self.encoding == PositionalEncoding::Learned {
+            vec![self.positional_encodings.view_mut().into_dyn()]
+        } else {
+            Vec::new()
+        }
+    }
+}
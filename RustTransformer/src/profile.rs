@@ -0,0 +1,137 @@
+use ndarray::Array1;
+use crate::dataset::AUTHOR_NAMES;
+use crate::dense::softmax;
+use crate::transformer::Transformer;
+use crate::Float;
+use log::info;
+
+/// The combined evidence that a set of messages was written by each author
+pub struct AuthorProfile {
+    /// The mean log-probability given to each author across the messages
+    pub mean_log_probs: Array1<Float>,
+    /// The probability of each author, treating the messages as independent evidence with the
+    /// model's training prior. This becomes overconfident when the messages are correlated, so the
+    /// mean log-probabilities are the safer score for comparing sets of different sizes.
+    pub posterior: Array1<Float>,
+    pub num_messages: usize,
+}
+
+impl AuthorProfile {
+    /// The authors ordered from most to least likely, with their posterior probability
+    pub fn ranking(&self) -> Vec<(usize, Float)> {
+        let mut ranking: Vec<(usize, Float)> = self.posterior.iter().copied().enumerate().collect();
+        ranking.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranking
+    }
+}
+
+/// Run the transformer on each message believed to come from one unknown author and
+/// aggregate the predictions into a single profile. The messages are predicted in parallel.
+pub fn profile_author(transformer: &Transformer, messages: &[String]) -> AuthorProfile {
+    let messages: Vec<String> = messages.iter().filter(|msg| !msg.trim().is_empty()).cloned().collect();
+    assert!(!messages.is_empty(), "Expected at least one non-empty message");
+    let num_messages = messages.len();
+
+    // Clamp the probabilities so a single confident message can't rule out an author entirely
+    let sum_log_probs = transformer.predict_batch(&messages).into_iter()
+        .map(|probs| probs.mapv(|p| p.max(Float::MIN_POSITIVE).ln()))
+        .reduce(|sum, log_probs| sum + log_probs)
+        .unwrap();
+
+    // Each prediction is proportional to the prior times the likelihood of its message, so the
+    // sum counts the prior once per message. Divide it out of all but one of them.
+    let mut log_posterior = sum_log_probs.clone();
+    if let Some(prior) = transformer.author_prior() {
+        log_posterior.scaled_add(-((num_messages - 1) as Float), &prior.mapv(|p| p.max(Float::MIN_POSITIVE).ln()));
+    }
+
+    AuthorProfile {
+        mean_log_probs: &sum_log_probs / num_messages as Float,
+        posterior: softmax(log_posterior),
+        num_messages,
+    }
+}
+
+/// Load the messages to profile, either as a JSON array of strings or as one message per line
+pub(crate) fn load_messages(messages_file: &str) -> Vec<String> {
+    let contents = std::fs::read_to_string(messages_file).expect("Failed to read file");
+    if messages_file.ends_with(".json") {
+        serde_json::from_str(&contents).unwrap()
+    } else {
+        contents.lines().map(|line| line.to_string()).collect()
+    }
+}
+
+/// Profile the author of a file of messages with a saved model and log the ranking
+pub fn run_profile(model_file: &str, messages_file: &str) {
+    let transformer = Transformer::load(model_file);
+    let messages = load_messages(messages_file);
+
+    let profile = profile_author(&transformer, &messages);
+    info!("Profiled {} messages", profile.num_messages);
+    for (rank, (author, probability)) in profile.ranking().into_iter().enumerate() {
+        info!("{}. {} (posterior: {:.4}, mean log-probability: {:.4})", rank + 1, AUTHOR_NAMES[author], probability, profile.mean_log_probs[author]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{arr1, Array1};
+    use super::profile_author;
+    use crate::config::TransformerConfig;
+    use crate::transformer::Transformer;
+    use crate::transformer::tests::small_transformer;
+    use crate::Float;
+
+    const MESSAGES: [&str; 3] = ["the cat sat", "on a mat", "and slept"];
+
+    fn transformer() -> Transformer {
+        small_transformer(TransformerConfig { num_words: 4, dimensionality: 8, hidden_layer_size: 16, ..TransformerConfig::default() })
+    }
+
+    /// Normalise the products of the predictions divided by the prior of all but one message
+    fn expected_posterior(predictions: &[Array1<Float>], prior: &Array1<Float>) -> Array1<Float> {
+        let mut product = prior.mapv(|p| p.powi(1 - predictions.len() as i32));
+        for prediction in predictions {
+            product *= prediction;
+        }
+        &product / product.sum()
+    }
+
+    #[test]
+    fn one_message_matches_the_prediction() {
+        let mut transformer = transformer();
+        transformer.set_author_prior(arr1(&[0.8, 0.2]));
+        let profile = profile_author(&transformer, &[MESSAGES[0].to_string()]);
+        let prediction = transformer.predict(MESSAGES[0]);
+        for (p, q) in profile.posterior.iter().zip(prediction.iter()) {
+            assert!((p - q).abs() < 1e-5, "{} != {}", profile.posterior, prediction);
+        }
+    }
+
+    #[test]
+    fn prior_is_counted_once() {
+        let messages: Vec<String> = MESSAGES.iter().map(|msg| msg.to_string()).collect();
+        let mut transformer = transformer();
+        let predictions = transformer.predict_batch(&messages);
+
+        // Without a recorded prior, the prior is uniform and the predictions are just multiplied
+        let uniform = profile_author(&transformer, &messages);
+        let expected = expected_posterior(&predictions, &arr1(&[0.5, 0.5]));
+        for (p, q) in uniform.posterior.iter().zip(expected.iter()) {
+            assert!((p - q).abs() < 1e-5, "{} != {}", uniform.posterior, expected);
+        }
+
+        let prior = arr1(&[0.9, 0.1]);
+        transformer.set_author_prior(prior.clone());
+        let profile = profile_author(&transformer, &messages);
+        let expected = expected_posterior(&predictions, &prior);
+        for (p, q) in profile.posterior.iter().zip(expected.iter()) {
+            assert!((p - q).abs() < 1e-5, "{} != {}", profile.posterior, expected);
+        }
+
+        // The mean log-probabilities don't depend on the prior
+        assert_eq!(profile.mean_log_probs, uniform.mean_log_probs);
+        assert!((profile.posterior.sum() - 1.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,201 @@
+use ndarray::{Array1, Array2, Axis};
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use crate::block::Block;
+use crate::dataset::load_chat_dataset;
+use crate::embedding::Embedding;
+use crate::run::{predicted_author, TEST_SIZE};
+use crate::transformer::Transformer;
+use log::info;
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+
+/// A trait for a block whose weight matrices can be converted to int8 for inference.
+/// The float weights are dropped, so a quantized block can't be trained and panics if
+/// it is forward propagated. Quantizing a block twice does nothing.
+pub trait Quantize {
+    fn quantize(&mut self);
+}
+
+/// Quantize each row of a matrix to int8 with its own scale, so rows with small values keep their precision
+fn quantize_rows<F: BlockFloat>(matrix: &Array2<F>) -> (Array2<i8>, Array1<F>) {
+    let max_int8 = F::constant(127.0);
+    let scales = matrix.map_axis(Axis(1), |row| {
+        let max = row.fold(F::zero(), |a, &b| a.max(b.abs()));
+        // Rows of zeros would otherwise divide by zero
+        if max > F::zero() { max / max_int8 } else { F::one() }
+    });
+    let values = Array2::from_shape_fn(matrix.raw_dim(), |(i, j)| (matrix[[i, j]] / scales[i]).round().to_i8().unwrap());
+
+    (values, scales)
+}
+
+// Defines an int8 weight matrix with one scale for each output channel
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuantizedMatrix<F = Float> {
+    /// The transposed weights, with a row for each output channel
+    values: Array2<i8>,
+    scales: Array1<F>,
+}
+
+impl<F: FloatStorage> QuantizedMatrix<F> {
+    /// Convert the scales to another float type
+    pub fn cast<G: FloatStorage>(&self) -> QuantizedMatrix<G> {
+        QuantizedMatrix { values: self.values.clone(), scales: self.scales.mapv(cast) }
+    }
+}
+
+impl<F: BlockFloat> QuantizedMatrix<F> {
+    /// Quantize a weight matrix which is multiplied as `input.dot(matrix)`
+    pub fn new(matrix: &Array2<F>) -> QuantizedMatrix<F> {
+        let (values, scales) = quantize_rows(&matrix.t().to_owned());
+        QuantizedMatrix { values, scales }
+    }
+
+    /// The float weights the int8 values stand for
+    pub fn dequantize(&self) -> Array2<F> {
+        (self.values.mapv(F::constant) * self.scales.view().insert_axis(Axis(1))).reversed_axes()
+    }
+
+    /// Multiply every row of the input by the weights. Each input row is quantized to int8
+    /// with its own scale, and the products are summed as integers before being scaled back.
+    pub fn matrix_product(&self, input: &Array2<F>) -> Array2<F> {
+        let (input_values, input_scales) = quantize_rows(input);
+        let sums = input_values.mapv(i32::from).dot(&self.values.mapv(i32::from).t());
+
+        let mut output = sums.mapv(F::constant);
+        output *= &input_scales.insert_axis(Axis(1));
+        output *= &self.scales;
+        output
+    }
+
+    /// Multiply a vector by the weights
+    pub fn vector_product(&self, input: &Array1<F>) -> Array1<F> {
+        let input = input.view().insert_axis(Axis(0)).to_owned();
+        self.matrix_product(&input).index_axis_move(Axis(0), 0)
+    }
+}
+
+/// Quantize a saved model, report how its accuracy on the test split compares with the float
+/// model, and save it alongside the original with an `_int8` suffix
+pub fn run_quantize(model_file_name: &str) {
+    let transformer = Transformer::load(model_file_name);
+    let config = transformer.config().clone();
+    let embedding = Embedding::from_file("../chatbot_arena_embeddings.json", &config);
+    let test_set = load_chat_dataset("../train.json", config.num_words, config.context_turns, transformer.tokenizer(), &embedding, TEST_SIZE);
+
+    let mut quantized = transformer.clone();
+    quantized.quantize();
+
+    // Compare the predictions of both models on every test message
+    let outputs: Vec<(Array1<Float>, Array1<Float>)> = test_set.par_iter().map(|example| {
+        (transformer.infer(example.input.clone()), quantized.infer(example.input.clone()))
+    }).collect();
+
+    let mut losses = [0.0; 2];
+    let mut correct = [0; 2];
+    let mut agreed = 0;
+    let mut max_diff: Float = 0.0;
+    let mut sum_diff = 0.0;
+    for (example, (float_output, int8_output)) in test_set.iter().zip(outputs.iter()) {
+        for (i, output) in [float_output, int8_output].into_iter().enumerate() {
+            losses[i] += -output[example.author].max(Float::MIN_POSITIVE).ln();
+            if predicted_author(output) == example.author {
+                correct[i] += 1;
+            }
+        }
+        if predicted_author(float_output) == predicted_author(int8_output) {
+            agreed += 1;
+        }
+        let diff = (float_output - int8_output).mapv(Float::abs).fold(0.0, |a: Float, &b| a.max(b));
+        max_diff = max_diff.max(diff);
+        sum_diff += diff;
+    }
+
+    let num_messages = test_set.len() as Float;
+    info!("Compared {} test messages", test_set.len());
+    info!("float TEST LOSS: {:.4}, TEST ACC: {:.4}", losses[0] / num_messages, correct[0] as Float / num_messages);
+    info!(" int8 TEST LOSS: {:.4}, TEST ACC: {:.4}", losses[1] / num_messages, correct[1] as Float / num_messages);
+    info!("Predictions agree on {:.4} of messages", agreed as Float / num_messages);
+    info!("Probability difference: mean {:.6}, max {:.6}", sum_diff / num_messages, max_diff);
+
+    let quantized_file_name = format!("{}_int8.json", model_file_name.trim_end_matches(".json"));
+    let quantized_file = std::fs::File::create(&quantized_file_name).unwrap();
+    serde_json::to_writer(quantized_file, &quantized).unwrap();
+    info!("Saved quantized model to {}", quantized_file_name);
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{arr1, Array1, Array2, Axis};
+    use rand::Rng;
+    use super::{Quantize, QuantizedMatrix};
+    use crate::activation::Activation;
+    use crate::block::Block;
+    use crate::dense::Dense;
+    use crate::Float;
+
+    fn random_matrix(rows: usize, cols: usize) -> Array2<Float> {
+        let mut rng = rand::thread_rng();
+        Array2::from_shape_fn((rows, cols), |_| rng.gen::<Float>() * 2.0 - 1.0)
+    }
+
+    /// The step between int8 values of each column, which is each output channel
+    fn column_steps(matrix: &Array2<Float>) -> Array1<Float> {
+        matrix.map_axis(Axis(0), |column| column.fold(0.0, |a: Float, &b| a.max(b.abs())) / 127.0)
+    }
+
+    #[test]
+    fn dequantize_is_within_half_a_step() {
+        let matrix = random_matrix(6, 5);
+        let error = QuantizedMatrix::new(&matrix).dequantize() - &matrix;
+        let steps = column_steps(&matrix);
+        for ((_, j), e) in error.indexed_iter() {
+            assert!(e.abs() <= steps[j] * 0.5001, "Error {} is more than half of the step {}", e, steps[j]);
+        }
+    }
+
+    #[test]
+    fn zero_channel_has_a_scale_of_one() {
+        let mut matrix = random_matrix(4, 3);
+        matrix.column_mut(1).fill(0.0);
+        let quantized = QuantizedMatrix::new(&matrix);
+
+        assert_eq!(quantized.scales[1], 1.0);
+        assert!(quantized.values.row(1).iter().all(|&x| x == 0));
+        assert!(quantized.dequantize().column(1).iter().all(|&x| x == 0.0));
+
+        // A row of zeros in the input has a scale of one too, and gives zero outputs
+        let output = quantized.matrix_product(&Array2::zeros((2, 4)));
+        assert!(output.iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn matrix_product_is_close_to_the_float_product() {
+        let matrix = random_matrix(8, 5);
+        let input = random_matrix(3, 8);
+        let quantized = QuantizedMatrix::new(&matrix);
+        let error = quantized.matrix_product(&input) - input.dot(&matrix);
+
+        // Both the input and the weights are rounded by at most half a step
+        let weight_steps = column_steps(&matrix);
+        let input_steps = column_steps(&input.t().to_owned());
+        for ((i, j), e) in error.indexed_iter() {
+            let bound: Float = (0..8).map(|k| {
+                input[[i, k]].abs() * weight_steps[j] * 0.5 + matrix[[k, j]].abs() * input_steps[i] * 0.5 + weight_steps[j] * input_steps[i] * 0.25
+            }).sum();
+            assert!(e.abs() <= bound * 1.001, "Error {} is more than the bound {}", e, bound);
+        }
+
+        let row = quantized.vector_product(&input.row(0).to_owned());
+        assert_eq!(row, quantized.matrix_product(&input).row(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be used for inference")]
+    fn quantized_dense_cannot_be_trained() {
+        let mut dense = Dense::<Float>::new(arr1(&[4, 3]), Activation::Relu);
+        dense.quantize();
+        dense.infer(Array1::ones(4));
+        dense.forward_propagate(Array1::ones(4));
+    }
+}
@@ -1,14 +1,22 @@
 // use std::io::Write;
 use serde_json;
 use crate::block::Block;
-use ndarray::arr1;
+use crate::config::TransformerConfig;
+use ndarray::{arr1, Array1, ArrayD};
 use rand::Rng;
-use crate::embedding::load_embeddings;
+use rand::seq::SliceRandom;
+use crate::embedding::Embedding;
+use crate::parameters::{apply_mean_update, snapshot, take_update};
 use crate::transformer::Transformer;
 use crate::dataset::{load_chat_dataset, Message};
+use crate::Float;
+use chat_core::stylometry::feature_names;
+use chat_core::tokenizer::Tokenizer;
 use log::info;
+use rayon::prelude::*;
+use std::thread;
 
-fn log_dataset_stats(dataset: &Vec<Message>) {
+pub(crate) fn log_dataset_stats(dataset: &[Message]) {
     info!("Loaded {} messages successfully.", dataset.len());
     let mut author_counts = [0; 2];
     for example in dataset {
@@ -17,14 +25,124 @@ fn log_dataset_stats(dataset: &Vec<Message>) {
     info!("Author counts: {:?}", author_counts);
 }
 
-pub fn run(num_words: usize, dimensionality: usize, num_encoders: usize, num_heads: usize, hidden_layer_size: usize, num_messages: usize) {
+/// Number of examples at the start of the dataset to test on
+pub(crate) const TEST_SIZE: usize = 2000;
+
+/// The fraction of the examples written by each author
+pub(crate) fn author_prior(dataset: &[Message]) -> Array1<Float> {
+    let mut counts = Array1::<Float>::zeros(2);
+    for example in dataset {
+        counts[example.author] += 1.0;
+    }
+    counts / dataset.len() as Float
+}
+
+/// The author given the highest probability by the transformer
+pub(crate) fn predicted_author(output: &Array1<Float>) -> usize {
+    let mut max_index = 0;
+    for i in 1..output.len() {
+        if output[i] > output[max_index] {
+            max_index = i;
+        }
+    }
+    max_index
+}
+
+/// Log how much the test accuracy drops when each stylometric feature is shuffled between messages
+fn log_feature_importance(transformer: &Transformer, test_set: &[Message]) {
+    let mut rng = rand::thread_rng();
+    let base_correct = test_set.par_iter().filter(|example| predicted_author(&transformer.infer(example.input.clone())) == example.author).count();
+    let base_acc = base_correct as Float / test_set.len() as Float;
+
+    let mut importances = Vec::new();
+    for (i, name) in feature_names().into_iter().enumerate() {
+        let mut permutation: Vec<usize> = (0..test_set.len()).collect();
+        permutation.shuffle(&mut rng);
+
+        // Give each message the value of this feature from a random other message
+        let correct = test_set.par_iter().zip(permutation.par_iter()).filter(|(example, &j)| {
+            let mut input = example.input.clone();
+            input.features[i] = test_set[j].input.features[i];
+            predicted_author(&transformer.infer(input)) == example.author
+        }).count();
+        importances.push((name, base_acc - correct as Float / test_set.len() as Float));
+    }
+
+    importances.sort_by(|a, b| b.1.total_cmp(&a.1));
+    info!("FEATURE IMPORTANCE (drop in test accuracy when shuffled)");
+    for (name, importance) in importances {
+        info!("{:>24}: {:.4}", name, importance);
+    }
+}
+
+/// Measure the importance of each stylometric feature to a saved model. Every feature needs its
+/// own pass over the test split, so this is a separate command rather than part of each test pass.
+pub fn run_feature_importance(model_file_name: &str) {
+    let transformer = Transformer::load(model_file_name);
+    let config = transformer.config().clone();
+    assert!(config.stylometric_features, "{} was trained without stylometric features", model_file_name);
+
+    let embedding = Embedding::from_file("../chatbot_arena_embeddings.json", &config);
+    let test_set = load_chat_dataset("../train.json", config.num_words, config.context_turns, transformer.tokenizer(), &embedding, TEST_SIZE);
+    log_feature_importance(&transformer, &test_set);
+}
+
+/// Train the transformer on a single example, returning the cross entropy loss and the predicted author
+fn train_example(transformer: &mut Transformer, example: &Message) -> (Float, usize) {
+    // Forward propagate the example through the transformer model
+    let val = transformer.forward_propagate(example.input.clone());
+
+    // Back propagate the author through the transformer model
+    let mut desired = arr1(&[0.0; 2]);
+    desired[example.author] = 1.0;
+    transformer.back_propagate(desired.clone());
+
+    // Calculate the cross entropy loss for the example
+    let mut loss = 0.0;
+    for i in 0..2 {
+        loss += -desired[i] * val[i].ln();
+    }
+
+    (loss, predicted_author(&val))
+}
+
+/// Train on a batch with one synchronous step. Each replica trains on its shard of the batch,
+/// resetting its parameters to the model's after every example so every update is computed
+/// from the same parameters, and the model then takes the mean update of the whole batch.
+fn train_batch(transformer: &mut Transformer, replicas: &mut [Transformer], batch: &[&Message]) -> Vec<(Float, usize)> {
+    let base = snapshot(transformer);
+    let shard_size = batch.len().div_ceil(replicas.len());
+
+    let (results, updates): (Vec<_>, Vec<_>) = thread::scope(|scope| {
+        let handles: Vec<_> = replicas.iter_mut().zip(batch.chunks(shard_size)).map(|(replica, shard)| {
+            let base = &base;
+            scope.spawn(move || {
+                let mut updates: Vec<ArrayD<Float>> = base.iter().map(|param| ArrayD::zeros(param.raw_dim())).collect();
+                let results: Vec<(Float, usize)> = shard.iter().map(|example| {
+                    let result = train_example(replica, example);
+                    take_update(replica, base, &mut updates);
+                    result
+                }).collect();
+                (results, updates)
+            })
+        }).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).unzip()
+    });
+
+    apply_mean_update(transformer, replicas, &updates, batch.len());
+    results.into_iter().flatten().collect()
+}
+
+/// Train the transformer, splitting the examples between `num_threads` replicas of the model
+pub fn run(config: TransformerConfig, tokenizer: Tokenizer, num_messages: usize, num_threads: usize) {
     let time = std::time::SystemTime::now();
     let str_time = time.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs().to_string();
-    let model_file_name = format!("{}_chtbt_model_{}_{}_{}_{}_{}.json", str_time, num_words, dimensionality, num_encoders, num_heads, hidden_layer_size);
-    let word_embeddings = load_embeddings("../chatbot_arena_embeddings.json");
-    let dataset = load_chat_dataset("../train.json", num_words, word_embeddings.clone(), num_messages);
+    let model_file_name = format!("{}_chtbt_model_{}_{}_{}_{}_{}.json", str_time, config.num_words, config.dimensionality, config.num_encoders, config.num_heads, config.hidden_layer_size);
+    let embedding = Embedding::from_file("../chatbot_arena_embeddings.json", &config);
+    let dataset = load_chat_dataset("../train.json", config.num_words, config.context_turns, &tokenizer, &embedding, num_messages);
     log_dataset_stats(&dataset);
-    let mut transformer = Transformer::new(num_words, dimensionality, num_encoders, num_heads, arr1(&[num_words*dimensionality,hidden_layer_size,num_words*dimensionality]), word_embeddings);
+    let mut transformer = Transformer::new(config, tokenizer, embedding);
+    transformer.set_author_prior(author_prior(&dataset[TEST_SIZE..]));
     // Load a pretrained model
     // let model_file = std::fs::File::open("1700084491_model_7_64_1_2_100.json").unwrap();
     // let mut transformer: Transformer = serde_json::from_reader(model_file).unwrap();
@@ -35,52 +153,49 @@ pub fn run(num_words: usize, dimensionality: usize, num_encoders: usize, num_hea
     let mut index = 0;
     let test_gaps = 10; // Test runs every N * test_gaps iterations
     let mut test_count = 0; 
-    const TEST_SIZE: usize = 2000; // Number of examples to test on
+    const SHARD_SIZE: usize = 10; // Number of examples each thread computes updates for in every step
     let mut avg_acc = 0.0;
 
     let mut confusion_matrix = [[0; 2]; 2];
 
     
+    // Each thread computes updates with its own replica of the model, which the model averages in one step
+    let mut replicas: Vec<Transformer> = if num_threads > 1 {
+        (0..num_threads).map(|_| transformer.clone()).collect()
+    } else {
+        Vec::new()
+    };
+
     loop {
-        // Select a random example from the dataset excluding the test set
-        let example = &dataset[rng.gen_range(TEST_SIZE..dataset.len())];
-        
-        // Forward propagate the example through the transformer model
-        let val = transformer.forward_propagate(example.msg.clone());
-        
-        // Back propagate the author through the transformer model
-        let mut desired = arr1(&[0.0; 2]);
-        desired[example.author] = 1.0;
-        transformer.back_propagate(desired.clone());
-
-        // Calculate the cross entropy loss for the example
-        for i in 0..2 {
-            avg_loss += -desired[i] * val[i].ln();
-        }
-        index += 1;
-
-        // Check if the model's prediction was correct
-        let mut max = 0.0;
-        let mut max_index = 0;
-        for i in 0..2 {
-            if val[i] > max {
-                max = val[i];
-                max_index = i;
+        // Select random examples from the dataset excluding the test set, one shard for each thread
+        let batch_size = if replicas.is_empty() { 1 } else { num_threads * SHARD_SIZE };
+        let batch: Vec<&Message> = (0..batch_size).map(|_| &dataset[rng.gen_range(TEST_SIZE..dataset.len())]).collect();
+
+        let results: Vec<(Float, usize)> = if replicas.is_empty() {
+            batch.iter().map(|example| train_example(&mut transformer, example)).collect()
+        } else {
+            train_batch(&mut transformer, &mut replicas, &batch)
+        };
+
+        for (example, (loss, predicted)) in batch.iter().zip(results) {
+            avg_loss += loss;
+            index += 1;
+
+            // Check if the model's prediction was correct
+            if predicted == example.author {
+                avg_acc += 1.0;
             }
-        }
-        if max_index == example.author {
-            avg_acc += 1.0;
-        }
 
-        // Update the confusion matrix
-        confusion_matrix[example.author][max_index] += 1;
+            // Update the confusion matrix
+            confusion_matrix[example.author][predicted] += 1;
+        }
 
-        if index == N {
+        if index >= N {
+            // Calculate and log the average loss for the current batch
+            info!("{} TRAIN LOSS: {:?}", model_file_name, avg_loss / index as Float);
+            info!("{}  TRAIN ACC: {:?}", model_file_name, avg_acc / index as Float);
             index = 0;
             test_count += 1;
-            // Calculate and log the average loss for the current batch
-            info!("{} TRAIN LOSS: {:?}", model_file_name, avg_loss / N as f32);
-            info!("{}  TRAIN ACC: {:?}", model_file_name, avg_acc / N as f32);
 
             // Print the confusion matrix
             // info!("CONFUSION MATRIX");
@@ -99,34 +214,25 @@ pub fn run(num_words: usize, dimensionality: usize, num_encoders: usize, num_hea
                 let mut avg_test_acc = 0.0;
                 let mut author_counts = [0; 2];
 
+                // Run the test set in parallel. Inference never applies dropout.
+                let test_set = &dataset[..TEST_SIZE];
+                let outputs: Vec<Array1<Float>> = test_set.par_iter().map(|example| transformer.infer(example.input.clone())).collect();
+
                 // Calculate the loss for each example in the test set
-                for i in 0..TEST_SIZE {
-                    let example = &dataset[i];
+                for (example, val) in test_set.iter().zip(outputs) {
                     author_counts[example.author] += 1;
-                    desired = arr1(&[0.0; 2]);
-                    desired[example.author] = 1.0;
-                    let val = transformer.forward_propagate(example.msg.clone());
-                    for i in 0..2 {
-                        avg_test_loss += -desired[i] * val[i].ln();
-                    }
+                    avg_test_loss += -val[example.author].ln();
+
                     // Check if the model's prediction was correct
-                    let mut max = 0.0;
-                    let mut max_index = 0;
-                    for i in 0..2 {
-                        if val[i] > max {
-                            max = val[i];
-                            max_index = i;
-                        }
-                    }
-                    if max_index == example.author {
+                    if predicted_author(&val) == example.author {
                         avg_test_acc += 1.0;
                     }
                 }
                 info!("Author counts: {:?}", author_counts);
                 
                 // Calculate and log the average loss for the test set
-                info!("{} TEST LOSS: {:?}", model_file_name, avg_test_loss / TEST_SIZE as f32);
-                info!("{}  TEST ACC: {:?}", model_file_name, avg_test_acc / TEST_SIZE as f32);
+                info!("{} TEST LOSS: {:?}", model_file_name, avg_test_loss / TEST_SIZE as Float);
+                info!("{}  TEST ACC: {:?}", model_file_name, avg_test_acc / TEST_SIZE as Float);
                 
                 let model_file = std::fs::File::create(&model_file_name).unwrap();
                 serde_json::to_writer(model_file, &transformer).unwrap();
@@ -138,4 +244,54 @@ pub fn run(num_words: usize, dimensionality: usize, num_encoders: usize, num_hea
         }
     
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{train_batch, train_example};
+    use crate::config::TransformerConfig;
+    use crate::dataset::Message;
+    use crate::parameters::Parameters;
+    use crate::transformer::tests::small_transformer;
+    use crate::Float;
+
+    const MESSAGES: [(&str, usize); 5] = [("the cat sat", 0), ("on a mat", 1), ("and slept", 0), ("a cat", 1), ("the mat sat on a cat", 0)];
+
+    #[test]
+    fn threads_match_a_single_thread_step() {
+        // Without dropout every replica computes the same updates, whichever thread it runs on
+        let config = TransformerConfig { num_words: 4, dimensionality: 8, hidden_layer_size: 16, dropout_rate: 0.0, ..TransformerConfig::default() };
+        let mut single = small_transformer(config);
+        let mut threaded = single.clone();
+        let mut one_by_one = single.clone();
+
+        let batch: Vec<Message> = MESSAGES.iter().map(|&(msg, author)| Message { input: single.message_input(msg, &[]), author }).collect();
+        let batch: Vec<&Message> = batch.iter().collect();
+
+        let mut replicas = vec![single.clone()];
+        let single_results = train_batch(&mut single, &mut replicas, &batch);
+        let mut replicas = vec![threaded.clone(), threaded.clone(), threaded.clone()];
+        let threaded_results = train_batch(&mut threaded, &mut replicas, &batch);
+        assert_eq!(single_results, threaded_results);
+
+        // The step is the mean of the updates of each example from the same parameters
+        let before: Vec<_> = one_by_one.parameters().iter().map(|param| param.to_owned()).collect();
+        let mut expected = before.clone();
+        for example in &batch {
+            let mut copy = one_by_one.clone();
+            train_example(&mut copy, example);
+            for ((expected, after), before) in expected.iter_mut().zip(copy.parameters()).zip(before.iter()) {
+                *expected += &((&after - before) / batch.len() as Float);
+            }
+        }
+
+        let close = |a: &Float, b: &Float| (a - b).abs() <= 1e-6 * (1.0 + b.abs());
+        for ((single, threaded), expected) in single.parameters().iter().zip(threaded.parameters().iter()).zip(expected.iter()) {
+            assert!(single.iter().zip(threaded.iter()).all(|(a, b)| close(a, b)));
+            assert!(single.iter().zip(expected.iter()).all(|(a, b)| close(a, b)));
+        }
+        for replica in replicas.iter_mut() {
+            assert_eq!(replica.parameters(), threaded.parameters());
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use ndarray::{Array1, Array2, ArrayViewMutD};
+use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use crate::LR;
+use rand_distr::{Distribution, Normal};
+use serde::{Serialize, Deserialize};
+
+// Defines a segment embedding struct, marking which turn of the conversation each word belongs to
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SegmentEmbedding<F = Float> {
+    segments: Array1::<usize>,
+    embeddings: Array2::<F>,
+}
+
+impl<F: FloatStorage> SegmentEmbedding<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> SegmentEmbedding<G> {
+        SegmentEmbedding { segments: self.segments.clone(), embeddings: self.embeddings.mapv(cast) }
+    }
+}
+
+impl<F: BlockFloat> SegmentEmbedding<F> {
+    /// Create a new segment embedding block for a sequence of `context_turns` previous turns,
+    /// each followed by a separator, and then the target message. Segment 0 is the target
+    /// message, and segment n is the turn n messages before it.
+    pub fn new(num_words: usize, context_turns: usize, cols: usize) -> SegmentEmbedding<F> {
+        let mut segments = Vec::with_capacity(context_turns * (num_words + 1) + num_words);
+        for turn in (1..=context_turns).rev() {
+            segments.extend(std::iter::repeat_n(turn, num_words + 1));
+        }
+        segments.extend(std::iter::repeat_n(0, num_words));
+
+        // Start with small random embeddings so segments are distinguishable
+        let normal = Normal::new(0.0, 0.02).unwrap();
+        let embeddings = Array2::<F>::from_shape_fn((context_turns + 1, cols), |_| F::constant(normal.sample(&mut rand::thread_rng())));
+
+        let block: SegmentEmbedding<F> = SegmentEmbedding {
+            segments: Array1::from_vec(segments),
+            embeddings,
+        };
+
+        block
+    }
+}
+
+impl<F: BlockFloat> Block for SegmentEmbedding<F> {
+    type Input = Array2<F>;
+    type Output = Array2<F>;
+
+    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        self.infer(value)
+    }
+
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        let mut output = value;
+
+        // Add the embedding of each word's segment
+        for (i, &segment) in self.segments.iter().enumerate() {
+            let mut row = output.row_mut(i);
+            row += &self.embeddings.row(segment);
+        }
+
+        output
+    }
+
+    fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        // Update each segment embedding with the error of every word in the segment
+        let lr = F::constant(LR);
+        for (i, &segment) in self.segments.iter().enumerate() {
+            self.embeddings.row_mut(segment).scaled_add(-lr, &error.row(i));
+        }
+
+        error
+    }
+}
+
+impl<F: BlockFloat> Parameters<F> for SegmentEmbedding<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        vec![self.embeddings.view_mut().into_dyn()]
+    }
+}
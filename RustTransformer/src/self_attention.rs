@@ -1,205 +1,284 @@
-use ndarray::{Array2, Array3, Axis, ArrayViewMut1};
+use ndarray::{Array1, Array2, Axis, ArrayViewMut1, ArrayViewMutD};
 use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::quantize::{Quantize, QuantizedMatrix};
+use crate::positional_encoder::{rotate, PositionalEncoding};
+use crate::{cast, BlockFloat, Float, FloatStorage};
 use crate::LR;
 use rand_distr::{Distribution, Normal};
 use serde::{Serialize, Deserialize};
 
 // Defines struct for storing key, query, and value matrices
-#[derive(Serialize, Deserialize)]
-pub struct SelfAttentionParams {
-    key: Array2::<f32>,
-    query: Array2::<f32>,
-    value: Array2::<f32>,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SelfAttentionParams<F = Float> {
+    key: Array2::<F>,
+    query: Array2::<F>,
+    value: Array2::<F>,
+}
+
+// Defines struct for storing int8 key, query, and value matrices
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuantizedSelfAttentionParams<F = Float> {
+    key: QuantizedMatrix<F>,
+    query: QuantizedMatrix<F>,
+    value: QuantizedMatrix<F>,
 }
 
 // Defines self-attention struct
-#[derive(Serialize, Deserialize)]
-pub struct SelfAttention {
-    input: Array2::<f32>,
-    weights: Array2::<f32>,
-    value_vecs: Array2::<f32>,
-    vec_key_matrix: Array3::<f32>,
-    vec_query_matrix: Array3::<f32>,
-    params: SelfAttentionParams,
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(bound(deserialize = "F: Deserialize<'de>"))]
+pub struct SelfAttention<F = Float> {
+    input: Array2::<F>,
+    weights: Array2::<F>,
+    value_vecs: Array2::<F>,
+    keys: Array2::<F>,
+    queries: Array2::<F>,
+    positional_encoding: PositionalEncoding,
+    alibi_slope: F,
+    params: SelfAttentionParams<F>,
+    #[serde(default)]
+    quantized: Option<QuantizedSelfAttentionParams<F>>,
 }
 
-impl SelfAttention {
-    /// Create a new self-attention block with the given parameters
-    pub fn new(rows: usize, cols: usize) -> SelfAttention {
-        let input = Array2::<f32>::zeros((rows, cols));
-        let mut key = Array2::<f32>::zeros((cols, cols));
-        let mut query = Array2::<f32>::zeros((cols, cols));
-        let mut value = Array2::<f32>::zeros((cols, cols));
+impl<F: FloatStorage> SelfAttention<F> {
+    /// Convert every value of the block to another float type
+    pub fn cast<G: FloatStorage>(&self) -> SelfAttention<G> {
+        SelfAttention {
+            input: self.input.mapv(cast),
+            weights: self.weights.mapv(cast),
+            value_vecs: self.value_vecs.mapv(cast),
+            keys: self.keys.mapv(cast),
+            queries: self.queries.mapv(cast),
+            positional_encoding: self.positional_encoding,
+            alibi_slope: cast(self.alibi_slope),
+            params: SelfAttentionParams {
+                key: self.params.key.mapv(cast),
+                query: self.params.query.mapv(cast),
+                value: self.params.value.mapv(cast),
+            },
+            quantized: self.quantized.as_ref().map(|quantized| QuantizedSelfAttentionParams {
+                key: quantized.key.cast(),
+                query: quantized.query.cast(),
+                value: quantized.value.cast(),
+            }),
+        }
+    }
+}
+
+impl<F: BlockFloat> SelfAttention<F> {
+    /// Create a new self-attention block with the given parameters. The ALiBi
+    /// slope is only used with `PositionalEncoding::Alibi`.
+    pub fn new(rows: usize, cols: usize, positional_encoding: PositionalEncoding, alibi_slope: Float) -> SelfAttention<F> {
+        let input = Array2::<F>::zeros((rows, cols));
+        let mut key = Array2::<F>::zeros((cols, cols));
+        let mut query = Array2::<F>::zeros((cols, cols));
+        let mut value = Array2::<F>::zeros((cols, cols));
 
         // Use He initialisation by using a mean of 0.0 and a standard deviation of sqrt(2/n)
-        let normal = Normal::new(0.0, (2.0/(rows*cols) as f32).sqrt()).unwrap();
-        key.mapv_inplace(|_| normal.sample(&mut rand::thread_rng()));
-        query.mapv_inplace(|_| normal.sample(&mut rand::thread_rng()));
-        value.mapv_inplace(|_| normal.sample(&mut rand::thread_rng()));
+        let normal = Normal::new(0.0, (2.0/(rows*cols) as f64).sqrt()).unwrap();
+        key.mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
+        query.mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
+        value.mapv_inplace(|_| F::constant(normal.sample(&mut rand::thread_rng())));
 
         // Store intermediary calculations for use in back-propagation
-        let weights = Array2::<f32>::zeros((input.shape()[0], input.shape()[1]));
-        let value_vecs = Array2::<f32>::zeros((input.shape()[0], input.shape()[1]));
-        let vec_key_matrix = Array3::<f32>::zeros((input.shape()[0], input.shape()[0], input.shape()[1]));
-        let vec_query_matrix = Array3::<f32>::zeros((input.shape()[0], input.shape()[0], input.shape()[1]));
+        let weights = Array2::<F>::zeros((rows, rows));
+        let value_vecs = Array2::<F>::zeros((rows, cols));
+        let keys = Array2::<F>::zeros((rows, cols));
+        let queries = Array2::<F>::zeros((rows, cols));
 
         let params = SelfAttentionParams { key, query, value };
 
-        let block: SelfAttention = SelfAttention {
+        let block: SelfAttention<F> = SelfAttention {
             input,
             weights,
             value_vecs,
-            vec_key_matrix,
-            vec_query_matrix,
-            params
+            keys,
+            queries,
+            positional_encoding,
+            alibi_slope: F::constant(alibi_slope),
+            params,
+            quantized: None,
         };
 
         block
     }
+
+    /// Calculate the queries, keys, attention weights and value vectors of the input,
+    /// using int8 matrices if they are given
+    fn attend(&self, input: &Array2<F>, quantized: Option<&QuantizedSelfAttentionParams<F>>) -> (Array2<F>, Array2<F>, Array2<F>, Array2<F>) {
+        // Multiply every input vector by the query, key and value matrices at once
+        let (mut queries, mut keys, value_vecs) = match quantized {
+            Some(quantized) => (quantized.query.matrix_product(input), quantized.key.matrix_product(input), quantized.value.matrix_product(input)),
+            None => (input.dot(&self.params.query), input.dot(&self.params.key), input.dot(&self.params.value)),
+        };
+
+        // Rotary encodings rotate the query and key by their positions
+        if self.positional_encoding == PositionalEncoding::Rotary {
+            rotate_rows(&mut queries, F::one());
+            rotate_rows(&mut keys, F::one());
+        }
+
+        // Find the similarity of every pair of words i and j with the dot product of query i and key j
+        let mut weights = queries.dot(&keys.t());
+
+        // ALiBi penalises attention between distant words
+        if self.positional_encoding == PositionalEncoding::Alibi {
+            let slope = self.alibi_slope;
+            for ((i, j), weight) in weights.indexed_iter_mut() {
+                *weight -= slope * F::constant(i.abs_diff(j));
+            }
+        }
+
+        // Normalize each weight vector using softmax
+        let padding = input.ncols().saturating_sub(input.nrows());
+        for x in weights.axis_iter_mut(Axis(0)) {
+            softmax(x, padding);
+        }
+
+        (queries, keys, weights, value_vecs)
+    }
 }
 
-// Apply softmax normalisation to an Array1.
-fn softmax(mut x: ArrayViewMut1<f32>) {
+// Apply softmax normalisation to an Array1, as if it were followed by `padding` zeros.
+// This is intentional: attention weights have always been normalised over one score per column
+// of the input, with zeros for the columns beyond the number of words. When there are fewer words
+// than columns, the padding takes some of the attention, so each row of weights sums to less
+// than one. The padding is kept so trained models keep their behaviour.
+fn softmax<F: BlockFloat>(mut x: ArrayViewMut1<F>, padding: usize) {
     // Iterate through the elements of the array to find the highest value.
-    let mut highest = 0.0;
+    let mut highest = F::zero();
     for i in 0..x.len() {
         if x[i] > highest {
             highest = x[i];
         }
     }
     x.mapv_inplace(|e| e - highest); // Subtract the highest value from each element in the array.
-    x.mapv_inplace(f32::exp); // Apply the exponential function to each element in the array.
+    x.mapv_inplace(F::exp); // Apply the exponential function to each element in the array.
 
-    let norm = x.sum(); // Compute the sum of all elements in the array.
+    let norm = x.sum() + F::constant(padding) * (-highest).exp(); // Compute the sum of all elements, including the padding.
 
     x.mapv_inplace(|e| e / norm); // Divide each element by the sum to normalize the array.
 }
 
-impl Block for SelfAttention {
-    type Input = Array2<f32>;
-    type Output = Array2<f32>;
+// Rotate each row of a matrix by its position, or by minus its position to undo the rotation.
+fn rotate_rows<F: BlockFloat>(matrix: &mut Array2<F>, direction: F) {
+    for (i, mut row) in matrix.axis_iter_mut(Axis(0)).enumerate() {
+        let mut rotated: Array1<F> = row.to_owned();
+        rotate(&mut rotated, direction * F::constant(i));
+        row.assign(&rotated);
+    }
+}
+
+impl<F: BlockFloat> Block for SelfAttention<F> {
+    type Input = Array2<F>;
+    type Output = Array2<F>;
 
     fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        assert!(self.quantized.is_none(), "A quantized self-attention block can only be used for inference");
+
+        // Store the intermediary calculations for use in back-propagation
+        (self.queries, self.keys, self.weights, self.value_vecs) = self.attend(&value, None);
         self.input = value;
 
-        // Generate context by finding weight vectors
-        self.weights = Array2::<f32>::zeros((self.input.shape()[0], self.input.shape()[1]));
-
-        for i in 0..self.input.shape()[0] {
-            for j in 0..self.input.shape()[0] {
-                // Find similarity of word i and word j by using their dot product
-                let vec_i = self.input.index_axis(Axis(0), i);
-                // Multiply vector inputs by query and key matrices
-                let vec_query = vec_i.dot(&self.params.query);
-                let vec_j = &self.input.index_axis(Axis(0), j);
-                let vec_key = &vec_j.dot(&self.params.key);
-                // Store intermediary values for use in back propagation
-                for k in 0..self.input.shape()[1] {
-                    self.vec_key_matrix[[i,j,k]] = vec_key[k];
-                    self.vec_query_matrix[[i,j,k]] = vec_query[k];
-                }
-                self.weights[[i,j]] = vec_query.dot(vec_key);
-            }
-        }
+        // Generate output as the weighted sum of the value vectors
+        self.weights.dot(&self.value_vecs)
+    }
 
-        // Normalize each weight vector using softmax
-        for x in self.weights.axis_iter_mut(Axis(0)) {
-            softmax(x);
-        }
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        let (_, _, weights, value_vecs) = self.attend(&value, self.quantized.as_ref());
+        weights.dot(&value_vecs)
+    }
 
-        // Generate output by calculating value vectors
-        let mut output = Array2::<f32>::zeros((self.input.shape()[0], self.input.shape()[1]));
+    fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
+        // Calculate the error with respect to the value vectors and the attention weights
+        let value_error = self.weights.t().dot(&error);
+        let weight_error = error.dot(&self.value_vecs.t());
 
-        // Apply value matrix to each vector before its use
-        self.value_vecs = Array2::<f32>::zeros((self.input.shape()[0], self.input.shape()[1]));
-        for i in 0..self.input.shape()[0] {
-            let vec_i = self.input.index_axis(Axis(0), i);
-            // Multiply each vector inputs by value matrix
-            let vec_value = vec_i.dot(&self.params.value);
-            self.value_vecs.row_mut(i).assign(&vec_value);
-        }
+        // Back propagate through the softmax of each row, using its Jacobian diag(a) - a·aᵀ.
+        // The padding scores have no value vectors, so they receive no error.
+        let weighted_sums = (&self.weights * &weight_error).sum_axis(Axis(1)).insert_axis(Axis(1));
+        let unnormalised_error = &self.weights * &(weight_error - &weighted_sums);
 
-        for i in 0..self.input.shape()[0] {
-            for j in 0..self.input.shape()[1] {
-                for k in 0..self.input.shape()[0] {
-                    output[[i,j]] += self.value_vecs[[k,j]] * self.weights[[i,k]];
-                }
-            }
+        // Calculate the error with respect to the queries and keys
+        let mut query_error = unnormalised_error.dot(&self.keys);
+        let mut key_error = unnormalised_error.t().dot(&self.queries);
+
+        // Undo the rotary rotation to find the errors before the positions were applied
+        if self.positional_encoding == PositionalEncoding::Rotary {
+            rotate_rows(&mut query_error, -F::one());
+            rotate_rows(&mut key_error, -F::one());
         }
 
-        output
+        // Find the error of the input through the unchanged query, key and value matrices
+        let prev_error = query_error.dot(&self.params.query.t())
+            + key_error.dot(&self.params.key.t())
+            + value_error.dot(&self.params.value.t());
+
+        // Update the parameters using the error, input values, and learning rate
+        let lr = F::constant(LR);
+        self.params.query.scaled_add(-lr, &self.input.t().dot(&query_error));
+        self.params.key.scaled_add(-lr, &self.input.t().dot(&key_error));
+        self.params.value.scaled_add(-lr, &self.input.t().dot(&value_error));
+
+        prev_error
     }
+}
 
-    fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
-        // Calculate the error with respect to the input values
-        let mut value_error = Array2::<f32>::zeros((self.input.shape()[0], self.input.shape()[1]));
-        // Calculate the weight update rate
-        let mut weight_rate = Array2::<f32>::zeros((self.input.shape()[0], self.input.shape()[1]));
-        
-        // Iterate over the columns (j) of the input
-        for j in 0..self.input.shape()[1] {
-            // Iterate over the rows (k) of the input
-            for k in 0..self.input.shape()[0] {
-                // Iterate over the rows (i) of the input
-                for i in 0..self.input.shape()[0] {
-                    // Accumulate the error by multiplying the error of the current row (i) with the corresponding weight
-                    value_error[[k, j]] += error[[i, j]] * self.weights[[i, k]];
-
-                    // Accumulate the weight update rate by multiplying the error of the current row (i)
-                    // with the corresponding value vector element
-                    weight_rate[[k, i]] += error[[k, j]] * self.value_vecs[[i, j]];
-                }
-                
-                // Iterate over the columns (l) of the input
-                for l in 0..self.input.shape()[1] {
-                    // Update the parameters using the accumulated error, input value, and learning rate
-                    self.params.value[[l, j]] -= value_error[[k, j]] * self.input.index_axis(Axis(0), k)[l] * LR;
-                }
-            }
-        }
+impl<F: BlockFloat> Parameters<F> for SelfAttention<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        vec![
+            self.params.key.view_mut().into_dyn(),
+            self.params.query.view_mut().into_dyn(),
+            self.params.value.view_mut().into_dyn(),
+        ]
+    }
+}
 
-        let mut prev_error = Array2::<f32>::zeros((self.input.shape()[0], self.input.shape()[1]));
-        let mut unnormalised_error = Array2::<f32>::zeros((self.input.shape()[0], self.input.shape()[1]));
-        let unchanged_key = self.params.key.clone();
-        let unchanged_query = self.params.query.clone();
-
-        // Iterate over the rows (i) of the input
-        for i in 0..self.input.shape()[0] {
-            // Iterate over the rows (j) of the input
-            for j in 0..self.input.shape()[0] {
-                // Iterate over the rows (k) of the input
-                for k in 0..self.input.shape()[0] {
-                    // Check if the current row (j) is equal to the current row (k)
-                    if j == k {
-                        // Calculate the rate of change of the output with respect to the input
-                        let output_rate = self.weights[[i, j]] * (1.0 - self.weights[[i, j]]);
-                        unnormalised_error[[i, j]] += output_rate * weight_rate[[i, j]];
-                    } else {
-                        let output_rate = -self.weights[[i, j]] * self.weights[[i, k]];
-                        unnormalised_error[[i, j]] += output_rate * weight_rate[[i, j]];
-                    }
-                }
-
-                // Iterate over the columns (k) of the input
-                for k in 0..self.input.shape()[1] {
-                    // Calculate the rate of change of the key and query vectors
-                    let key_rate = self.vec_query_matrix[[i, j, k]] * unnormalised_error[[i, j]];
-                    let query_rate = self.vec_key_matrix[[i, j, k]] * unnormalised_error[[i, j]];
-
-                    // Iterate over the columns (l) of the input
-                    for l in 0..self.input.shape()[1] {
-                        // Update the key and query parameters using the calculated rates, input values, and learning rate
-                        self.params.key[[l, k]] -= key_rate * self.input[[i, l]] * LR;
-                        self.params.query[[l, k]] -= query_rate * self.input[[i, l]] * LR;
-
-                        // Accumulate the previous error by multiplying the rates with the unchanged key and query values
-                        prev_error[[i, l]] += key_rate * unchanged_key[[l, k]];
-                        prev_error[[i, l]] += query_rate * unchanged_query[[l, k]];
-                    }
-                }
-            }
+impl<F: BlockFloat> Quantize for SelfAttention<F> {
+    fn quantize(&mut self) {
+        if self.quantized.is_some() {
+            return;
         }
+        self.quantized = Some(QuantizedSelfAttentionParams {
+            key: QuantizedMatrix::new(&self.params.key),
+            query: QuantizedMatrix::new(&self.params.query),
+            value: QuantizedMatrix::new(&self.params.value),
+        });
+        self.params.key = Array2::<F>::zeros((0, 0));
+        self.params.query = Array2::<F>::zeros((0, 0));
+        self.params.value = Array2::<F>::zeros((0, 0));
+    }
+}
 
-        prev_error
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+    use rand::Rng;
+    use super::SelfAttention;
+    use crate::block::tests::check_gradients;
+    use crate::positional_encoder::PositionalEncoding;
+    use crate::Float;
+
+    /// Three words with four columns, so the softmax is padded with one zero score
+    fn check_encoding(positional_encoding: PositionalEncoding) {
+        let mut rng = rand::thread_rng();
+        let input = Array2::from_shape_fn((3, 4), |_| rng.gen::<Float>() * 2.0 - 1.0);
+        check_gradients(&SelfAttention::new(3, 4, positional_encoding, 0.5), &input, 2e-2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        check_encoding(PositionalEncoding::Sinusoidal);
+    }
+
+    #[test]
+    fn rotary_gradients_match_finite_differences() {
+        check_encoding(PositionalEncoding::Rotary);
+    }
+
+    #[test]
+    fn alibi_gradients_match_finite_differences() {
+        check_encoding(PositionalEncoding::Alibi);
+    }
+}
@@ -0,0 +1,280 @@
+use ndarray::{Array1, ArrayD};
+use rand::Rng;
+use crate::config::TransformerConfig;
+use crate::dataset::{load_chat_dataset, MessageInput, AUTHOR_NAMES};
+use crate::embedding::Embedding;
+use crate::open_set::equal_error_rate;
+use crate::profile::load_messages;
+use crate::run::log_dataset_stats;
+use crate::transformer::Transformer;
+use crate::Float;
+use chat_core::tokenizer::Tokenizer;
+use log::info;
+use rayon::prelude::*;
+
+/// Pairs from different authors are pushed apart until their cosine similarity falls below this
+const MARGIN: Float = 0.5;
+
+/// The cosine similarity of two author embeddings
+pub fn cosine_similarity(a: &Array1<Float>, b: &Array1<Float>) -> Float {
+    a.dot(b) / (a.dot(a).sqrt() * b.dot(b).sqrt()).max(Float::EPSILON)
+}
+
+/// The gradient of the cosine similarity of `a` and `b` with respect to `a`
+fn cosine_gradient(a: &Array1<Float>, b: &Array1<Float>, similarity: Float) -> Array1<Float> {
+    let norm_a = a.dot(a).sqrt().max(Float::EPSILON);
+    let norm_b = b.dot(b).sqrt().max(Float::EPSILON);
+    b / (norm_a * norm_b) - a * (similarity / (norm_a * norm_a))
+}
+
+/// The contrastive loss of a pair with the given similarity, and its derivative with respect to the similarity
+fn contrastive_loss(similarity: Float, same_author: bool) -> (Float, Float) {
+    if same_author {
+        (1.0 - similarity, -1.0)
+    } else if similarity > MARGIN {
+        (similarity - MARGIN, 1.0)
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+/// Train a pair of messages with the contrastive loss, returning the loss.
+/// Both messages share the same encoder, but each block caches the state of one forward pass,
+/// so the second message is encoded by a copy of everything after the word embeddings. Both
+/// errors are then back propagated through the passes that produced them, with the same weights
+/// and dropout masks. The word vectors are looked up by the transformer itself, so the embedding
+/// and its vocabulary, the largest part of the model, are never copied.
+fn train_pair(transformer: &mut Transformer, first: &MessageInput, second: &MessageInput, same_author: bool) -> Float {
+    let mut second_pass = transformer.copy_encoder();
+    let second_embedded = transformer.embed(second.clone());
+    let b = second_pass.encode_embedded(second_embedded);
+    let a = transformer.encode(first.clone());
+    let similarity = cosine_similarity(&a, &b);
+    let (loss, derivative) = contrastive_loss(similarity, same_author);
+
+    if derivative != 0.0 {
+        let a_error = cosine_gradient(&a, &b, similarity) * derivative;
+        let b_error = cosine_gradient(&b, &a, similarity) * derivative;
+
+        // Take the update of the second pass before the first pass changes the shared weights
+        let second_embedding_error = second_pass.back_propagate_embedded(b_error);
+        let updates: Vec<ArrayD<Float>> = second_pass.encoder_parameters().iter().zip(transformer.encoder_parameters().iter()).map(|(after, before)| after - before).collect();
+
+        transformer.back_propagate_encoding(a_error);
+        for (mut param, update) in transformer.encoder_parameters().into_iter().zip(updates) {
+            param += &update;
+        }
+
+        // The error of a word vector doesn't depend on the vector, so the second message's
+        // words can be looked up again and updated after the first
+        transformer.embed(second.clone());
+        transformer.back_propagate_embedding(second_embedding_error);
+    }
+
+    loss
+}
+
+/// Train the encoder stack as a shared embedding network over pairs of messages, so
+/// messages can be verified as coming from the same author by cosine similarity
+pub fn run(config: TransformerConfig, tokenizer: Tokenizer, num_messages: usize) {
+    let time = std::time::SystemTime::now();
+    let str_time = time.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs().to_string();
+    let model_file_name = format!("{}_chtbt_siamese_{}_{}_{}_{}_{}.json", str_time, config.num_words, config.dimensionality, config.num_encoders, config.num_heads, config.hidden_layer_size);
+    let embedding = Embedding::from_file("../chatbot_arena_embeddings.json", &config);
+    let dataset = load_chat_dataset("../train.json", config.num_words, config.context_turns, &tokenizer, &embedding, num_messages);
+    log_dataset_stats(&dataset);
+    let mut transformer = Transformer::new(config, tokenizer, embedding);
+    let mut rng = rand::thread_rng();
+
+    const N: usize = 5000; // Number of values to average over
+    let test_gaps = 10; // Test runs every N * test_gaps iterations
+    const TEST_SIZE: usize = 2000; // Number of examples to test on
+
+    // Group the training examples by author to sample pairs
+    let mut by_author: Vec<Vec<usize>> = vec![Vec::new(); AUTHOR_NAMES.len()];
+    for (i, example) in dataset.iter().enumerate().skip(TEST_SIZE) {
+        by_author[example.author].push(i);
+    }
+    for (author, examples) in by_author.iter().enumerate() {
+        assert!(!examples.is_empty(), "Sampling pairs needs training messages from every author, but {} has none", AUTHOR_NAMES[author]);
+    }
+
+    let mut avg_loss = 0.0;
+    let mut index = 0;
+    let mut test_count = 0;
+    loop {
+        // Pair a random example with another from the same author half of the time
+        let first = &dataset[rng.gen_range(TEST_SIZE..dataset.len())];
+        let same_author = rng.gen_bool(0.5);
+        let author = if same_author {
+            first.author
+        } else {
+            (first.author + rng.gen_range(1..AUTHOR_NAMES.len())) % AUTHOR_NAMES.len()
+        };
+        let second = &dataset[by_author[author][rng.gen_range(0..by_author[author].len())]];
+
+        avg_loss += train_pair(&mut transformer, &first.input, &second.input, same_author);
+        index += 1;
+
+        if index == N {
+            index = 0;
+            test_count += 1;
+            info!("{} TRAIN LOSS: {:?}", model_file_name, avg_loss / N as Float);
+            avg_loss = 0.0;
+
+            if test_count == test_gaps {
+                test_count = 0;
+
+                // Compare each test example with the next, separating same-author and different-author pairs
+                let embeddings: Vec<Array1<Float>> = dataset[..TEST_SIZE].par_iter().map(|example| transformer.infer_encoding(example.input.clone())).collect();
+                let mut same_scores = Vec::new();
+                let mut different_scores = Vec::new();
+                for i in 0..TEST_SIZE - 1 {
+                    let similarity = cosine_similarity(&embeddings[i], &embeddings[i + 1]);
+                    if dataset[i].author == dataset[i + 1].author {
+                        same_scores.push(similarity);
+                    } else {
+                        different_scores.push(similarity);
+                    }
+                }
+
+                let rates = equal_error_rate(&same_scores, &different_scores);
+                info!("{} TEST EER: threshold {:.4}, FAR {:.4}, FRR {:.4}", model_file_name, rates.threshold, rates.false_accept_rate, rates.false_reject_rate);
+
+                let model_file = std::fs::File::create(&model_file_name).unwrap();
+                serde_json::to_writer(model_file, &transformer).unwrap();
+                info!("Saved model to {}", model_file_name);
+            }
+        }
+    }
+}
+
+/// Compare every pair of messages in a file with a saved Siamese model, logging the pairs
+/// from the most to the least similar. Pairs above the threshold the training run logs at
+/// its equal error rate are likely to come from the same author.
+pub fn run_compare(model_file_name: &str, messages_file: &str) {
+    let transformer = Transformer::load(model_file_name);
+    let messages: Vec<String> = load_messages(messages_file).into_iter().filter(|msg| !msg.trim().is_empty()).collect();
+
+    // Embed the messages in parallel
+    let embeddings: Vec<Array1<Float>> = messages.par_iter().map(|msg| transformer.infer_encoding(transformer.message_input(msg, &[]))).collect();
+
+    let mut pairs = Vec::new();
+    for i in 0..messages.len() {
+        for j in i + 1..messages.len() {
+            pairs.push((i, j, cosine_similarity(&embeddings[i], &embeddings[j])));
+        }
+    }
+    pairs.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+    info!("Compared {} pairs of {} messages", pairs.len(), messages.len());
+    for (i, j, similarity) in pairs {
+        info!("{:.4}: {:?} and {:?}", similarity, messages[i], messages[j]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::{arr1, Array1};
+    use rand::Rng;
+    use crate::activation::Activation;
+    use super::{contrastive_loss, cosine_gradient, cosine_similarity, train_pair, MARGIN};
+    use crate::block::tests::{assert_close, STEP};
+    use crate::config::TransformerConfig;
+    use crate::embedding::EmbeddingMode;
+    use crate::parameters::Parameters;
+    use crate::positional_encoder::PositionalEncoding;
+    use crate::transformer::tests::small_transformer;
+    use crate::transformer::Transformer;
+    use crate::{Float, LR};
+
+    #[test]
+    fn cosine_gradient_matches_finite_differences() {
+        let mut rng = rand::thread_rng();
+        let a = Array1::from_shape_fn(6, |_| rng.gen::<Float>() - 0.5);
+        let b = Array1::from_shape_fn(6, |_| rng.gen::<Float>() - 0.5);
+        let analytic = cosine_gradient(&a, &b, cosine_similarity(&a, &b));
+
+        let numeric: Vec<Float> = (0..a.len()).map(|k| {
+            let mut plus = a.clone();
+            let mut minus = a.clone();
+            plus[k] += STEP;
+            minus[k] -= STEP;
+            (cosine_similarity(&plus, &b) - cosine_similarity(&minus, &b)) / (2.0 * STEP)
+        }).collect();
+        assert_close("cosine", &analytic.to_vec(), &numeric, 1e-2, 1e-3);
+    }
+
+    #[test]
+    fn contrastive_derivative_matches_finite_differences() {
+        for (similarity, same_author) in [(0.3, true), (-0.4, true), (0.8, false), (0.2, false), (-0.6, false)] {
+            let (_, derivative) = contrastive_loss(similarity, same_author);
+            let numeric = (contrastive_loss(similarity + STEP, same_author).0 - contrastive_loss(similarity - STEP, same_author).0) / (2.0 * STEP);
+            assert!((derivative - numeric).abs() < 1e-3, "derivative at {} is {}, but finite differences give {}", similarity, derivative, numeric);
+        }
+        assert_eq!(contrastive_loss(MARGIN - 0.1, false), (0.0, 0.0));
+    }
+
+    /// The contrastive loss of a pair, computed without storing anything for back propagation
+    fn pair_loss(transformer: &Transformer, first: &[&str], second: &[&str], same_author: bool) -> Float {
+        let a = transformer.infer_encoding(transformer.message_input(&first.join(" "), &[]));
+        let b = transformer.infer_encoding(transformer.message_input(&second.join(" "), &[]));
+        contrastive_loss(cosine_similarity(&a, &b), same_author).0
+    }
+
+    #[test]
+    fn train_pair_matches_finite_differences() {
+        // Every word is in the vocabulary and no message is padded, so every word vector used is trained,
+        // and the positional encodings are learned so every parameter is trained. GELU has no kink
+        // for the finite differences to step over.
+        let config = TransformerConfig {
+            num_words: 4,
+            dimensionality: 4,
+            hidden_layer_size: 8,
+            dropout_rate: 0.0,
+            embedding_mode: EmbeddingMode::FineTune,
+            positional_encoding: PositionalEncoding::Learned,
+            feed_forward_activation: Activation::Gelu,
+            ..TransformerConfig::default()
+        };
+        let first = ["the", "cat", "sat", "on"];
+        let second = ["a", "cat", "and", "mat"];
+
+        for same_author in [true, false] {
+            let mut transformer = small_transformer(config.clone());
+            let a = transformer.infer_encoding(transformer.message_input(&first.join(" "), &[]));
+            let b = transformer.infer_encoding(transformer.message_input(&second.join(" "), &[]));
+            if contrastive_loss(cosine_similarity(&a, &b), same_author).1 == 0.0 {
+                continue;
+            }
+
+            let mut trained = transformer.clone();
+            let first_input = transformer.message_input(&first.join(" "), &[]);
+            let second_input = transformer.message_input(&second.join(" "), &[]);
+            train_pair(&mut trained, &first_input, &second_input, same_author);
+
+            let before: Vec<Vec<Float>> = transformer.parameters().iter().map(|p| p.iter().copied().collect()).collect();
+            let after: Vec<Vec<Float>> = trained.parameters().iter().map(|p| p.iter().copied().collect()).collect();
+            for (p, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+                let analytic: Vec<Float> = before.iter().zip(after.iter()).map(|(b, a)| (b - a) / LR).collect();
+                let numeric: Vec<Float> = (0..before.len()).map(|k| {
+                    let mut plus = transformer.clone();
+                    let mut minus = transformer.clone();
+                    *plus.parameters()[p].iter_mut().nth(k).unwrap() += STEP;
+                    *minus.parameters()[p].iter_mut().nth(k).unwrap() -= STEP;
+                    (pair_loss(&plus, &first, &second, same_author) - pair_loss(&minus, &first, &second, same_author)) / (2.0 * STEP)
+                }).collect();
+                let update_noise = 2.0 * Float::EPSILON * before.iter().fold(0.0, |a: Float, &b| a.max(b.abs())) / LR;
+                let loss_noise = 10.0 * Float::EPSILON / STEP;
+                assert_close(&format!("parameter {}", p), &analytic, &numeric, 2e-2, loss_noise + update_noise);
+            }
+        }
+    }
+
+    #[test]
+    fn identical_vectors_are_similar() {
+        let a = arr1(&[1.0, -2.0, 0.5]);
+        assert!((cosine_similarity(&a, &a) - 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&a, &-&a) + 1.0).abs() < 1e-6);
+    }
+}
@@ -1,72 +1,386 @@
-use ndarray::{Array1, Array2, arr1};
-use std::collections::HashMap;
+use ndarray::{concatenate, s, Array1, Array2, ArrayViewMutD, Axis, arr1};
+use crate::activation::Activation;
 use crate::block::Block;
+use crate::parameters::Parameters;
+use crate::quantize::Quantize;
+use crate::char_encoder::CharEncoder;
+use crate::config::{NormPlacement, TransformerConfig};
+use crate::dataset::{pad_msg, MessageInput};
 use crate::dense::Dense;
+use crate::dropout::Dropout;
+use crate::embedding::{Embedding, PAD_TOKEN, SEP_TOKEN};
 use crate::encoder_block::EncoderBlock;
+use crate::layer_norm::LayerNorm;
 use crate::positional_encoder::PositionalEncoder;
+use crate::segment_embedding::SegmentEmbedding;
+use crate::{cast, BlockFloat, Float, FloatStorage};
+use chat_core::stylometry::NUM_FEATURES;
+use chat_core::tokenizer::Tokenizer;
+use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 
 // Defines attention heads and dense layer.
-#[derive(Serialize, Deserialize)]
-pub struct TransformerParams {
-    encoder_blocks: Array1::<EncoderBlock>,
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(bound(deserialize = "F: Deserialize<'de>"))]
+pub struct TransformerParams<F = Float> {
+    encoder_blocks: Array1::<EncoderBlock<F>>,
+    /// Normalises the output of the encoder stack when the blocks normalise their inputs,
+    /// as the residual stream is otherwise never normalised
+    #[serde(default)]
+    final_norm: Option<LayerNorm<F>>,
 }
 
 // Defines multi-headed attention struct
-#[derive(Serialize, Deserialize)]
-pub struct Transformer {
-    input: Array1::<String>,
-    output: Array1::<f32>,
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(bound(deserialize = "F: Deserialize<'de>"))]
+pub struct Transformer<F = Float> {
+    input: MessageInput,
+    output: Array1::<F>,
     num_words: usize,
+    sequence_length: usize,
     dimensionality: usize,
-    pos_encoder: PositionalEncoder,
-    classifier: Dense,
-    embedding: HashMap<String, Vec<f32>>,
-    params: TransformerParams,
+    config: TransformerConfig,
+    segment_embedding: Option<SegmentEmbedding<F>>,
+    pos_encoder: PositionalEncoder<F>,
+    embedding_dropout: Dropout<F>,
+    char_encoder: Option<CharEncoder<F>>,
+    classifier: Dense<F>,
+    tokenizer: Tokenizer,
+    embedding: Embedding<F>,
+    params: TransformerParams<F>,
+    /// The fraction of the training messages written by each author, which every prediction
+    /// includes. Models saved without it are assumed to have been trained on a uniform prior.
+    #[serde(default)]
+    author_prior: Option<Array1<F>>,
 }
 
 impl Transformer {
+    /// Load a model saved during training. With the `half` feature, models saved with 16-bit
+    /// weights by `HalfTransformer` are loaded too, and converted back to `Float`.
+    pub fn load(file_name: &str) -> Transformer {
+        let json = std::fs::read(file_name).expect("Failed to read file");
+        #[cfg(feature = "half")]
+        if crate::half_precision::is_half_model(&json) {
+            let model: crate::half_precision::HalfTransformer = serde_json::from_slice(&json).expect("Failed to parse model");
+            return model.to_transformer();
+        }
+        serde_json::from_slice(&json).expect("Failed to parse model")
+    }
+}
+
+impl<F: FloatStorage> Transformer<F> {
+    /// Convert every value of the model to another float type
+    pub fn cast<G: FloatStorage>(&self) -> Transformer<G> {
+        Transformer {
+            input: self.input.clone(),
+            output: self.output.mapv(cast),
+            num_words: self.num_words,
+            sequence_length: self.sequence_length,
+            dimensionality: self.dimensionality,
+            config: self.config.clone(),
+            segment_embedding: self.segment_embedding.as_ref().map(SegmentEmbedding::cast),
+            pos_encoder: self.pos_encoder.cast(),
+            embedding_dropout: self.embedding_dropout.cast(),
+            char_encoder: self.char_encoder.as_ref().map(CharEncoder::cast),
+            classifier: self.classifier.cast(),
+            tokenizer: self.tokenizer.clone(),
+            embedding: self.embedding.cast(),
+            params: TransformerParams {
+                encoder_blocks: self.params.encoder_blocks.iter().map(EncoderBlock::cast).collect(),
+                final_norm: self.params.final_norm.as_ref().map(LayerNorm::cast),
+            },
+            author_prior: self.author_prior.as_ref().map(|prior| prior.mapv(cast)),
+        }
+    }
+}
+
+impl<F: BlockFloat> Transformer<F> {
     /// Create a new self-attention block with the given parameters
-    pub fn new(num_words: usize, dimensionality: usize, num_encoders: usize, num_heads: usize, layer_sizes: Array1<usize>, embedding: HashMap<String, Vec<f32>>) -> Transformer {
-        let encoder_blocks = Array1::from_shape_fn(num_encoders, |_| EncoderBlock::new(num_words, dimensionality, num_heads, layer_sizes.clone()));
-        let params = TransformerParams { encoder_blocks };
-        let pos_encoder = PositionalEncoder::new(num_words, dimensionality);
-        let classifier = Dense::new(arr1(&[num_words*dimensionality, 2]), false, true);
-        let block: Transformer = Transformer {
-            input: Array1::from_shape_fn(num_words, |_| "".to_string()),
-            output: Array1::<f32>::zeros(2),
+    pub fn new(config: TransformerConfig, tokenizer: Tokenizer, embedding: Embedding<F>) -> Transformer<F> {
+        let num_words = config.num_words;
+        let sequence_length = config.sequence_length();
+        let dimensionality = config.dimensionality;
+        let encoder_blocks = Array1::from_shape_fn(config.num_encoders, |_| EncoderBlock::new(&config));
+        let final_norm = (config.norm_placement == NormPlacement::Pre).then(|| LayerNorm::new(sequence_length, dimensionality, config.layer_norm_epsilon));
+        let params = TransformerParams { encoder_blocks, final_norm };
+        let segment_embedding = (config.context_turns > 0).then(|| SegmentEmbedding::new(num_words, config.context_turns, dimensionality));
+        let pos_encoder = PositionalEncoder::new(sequence_length, dimensionality, config.positional_encoding);
+        let embedding_dropout = Dropout::new(sequence_length, dimensionality, config.dropout_rate);
+        let char_encoder = config.char_encoder.as_ref().map(CharEncoder::new);
+        let char_features = char_encoder.as_ref().map_or(0, |encoder| encoder.output_size());
+        let stylometric_features = if config.stylometric_features { NUM_FEATURES } else { 0 };
+        let classifier = Dense::new(arr1(&[sequence_length*dimensionality + char_features + stylometric_features, 2]), Activation::Softmax);
+        let block: Transformer<F> = Transformer {
+            input: MessageInput::default(),
+            output: Array1::<F>::zeros(2),
             num_words,
+            sequence_length,
             dimensionality,
+            config,
+            segment_embedding,
             pos_encoder,
+            embedding_dropout,
+            char_encoder,
             classifier,
+            tokenizer,
             embedding,
-            params
+            params,
+            author_prior: None,
         };
 
         block
     }
-}
 
-impl Block for Transformer {
-    type Input = Array1<String>;
-    type Output = Array1<f32>;
+    /// The hyperparameters the transformer was built with
+    pub fn config(&self) -> &TransformerConfig {
+        &self.config
+    }
 
-    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+    /// Tokenize a message which replies to the `previous` turns of a conversation, oldest first,
+    /// the same way as the training data
+    pub fn message_input(&self, msg: &str, previous: &[&str]) -> MessageInput {
+        let words = pad_msg(self.tokenizer.tokenize(msg), self.num_words);
+        let context = previous.iter().map(|turn| pad_msg(self.tokenizer.tokenize(turn), self.num_words)).collect();
+        MessageInput::new(words, msg.to_string(), context)
+    }
+
+    /// The tokenizer the transformer was trained with
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// The fraction of the training messages written by each author, if it was recorded
+    pub fn author_prior(&self) -> Option<&Array1<F>> {
+        self.author_prior.as_ref()
+    }
+
+    /// Record the fraction of the training messages written by each author
+    pub fn set_author_prior(&mut self, author_prior: Array1<F>) {
+        assert_eq!(author_prior.len(), self.output.len(), "Expected a prior for every author");
+        self.author_prior = Some(author_prior);
+    }
+
+    /// Predict the author of a message
+    pub fn predict(&self, msg: &str) -> Array1<F> {
+        self.predict_in_context(msg, &[])
+    }
+
+    /// Predict the author of a message which replies to the `previous` turns of a conversation, oldest first
+    pub fn predict_in_context(&self, msg: &str, previous: &[&str]) -> Array1<F> {
+        self.infer(self.message_input(msg, previous))
+    }
+
+    /// Predict the author of every message in parallel, keeping their order
+    pub fn predict_batch(&self, msgs: &[String]) -> Vec<Array1<F>> {
+        msgs.par_iter().map(|msg| self.predict(msg)).collect()
+    }
+
+    /// The classifier's scores for each author of a message, before the softmax
+    pub fn predict_logits(&self, msg: &str) -> Array1<F> {
+        self.classifier.infer_logits(self.infer_features(&self.message_input(msg, &[])))
+    }
+
+    /// Lay out the context turns, each followed by a separator, before the words of the message.
+    /// Missing turns at the start of a conversation are filled with padding.
+    fn sequence_words(&self, input: &MessageInput) -> Array1<String> {
+        let mut sequence = Vec::with_capacity(self.sequence_length);
+        let context_turns = self.config.context_turns;
+        let missing_turns = context_turns.saturating_sub(input.context.len());
+        let context = &input.context[input.context.len().saturating_sub(context_turns)..];
+
+        for _ in 0..missing_turns {
+            sequence.extend(std::iter::repeat_n(PAD_TOKEN.to_string(), self.num_words));
+            sequence.push(SEP_TOKEN.to_string());
+        }
+        for turn in context {
+            sequence.extend(turn.iter().cloned());
+            sequence.push(SEP_TOKEN.to_string());
+        }
+        sequence.extend(input.words.iter().cloned());
+
+        Array1::from_vec(sequence)
+    }
+
+    /// The classifier's scores for each author from the last forward pass, before the softmax
+    pub fn logits(&self) -> Array1<F> {
+        self.classifier.logits().clone()
+    }
+
+    /// Encode a message into an author embedding by mean-pooling the output of the encoder stack.
+    /// The classifier isn't used, so embeddings can be compared between authors it was never trained on.
+    pub fn encode(&mut self, value: MessageInput) -> Array1<F> {
+        let embedded = self.embed(value);
+        self.encode_embedded(embedded)
+    }
+
+    /// Encode a message into an author embedding without storing anything for back propagation
+    pub fn infer_encoding(&self, value: MessageInput) -> Array1<F> {
+        self.infer_sequence(&value).mean_axis(Axis(0)).unwrap()
+    }
+
+    /// Back propagate the error of the author embedding from the last call to `encode`
+    pub fn back_propagate_encoding(&mut self, error: Array1<F>) {
+        let embedding_error = self.back_propagate_embedded(error);
+        self.embedding.back_propagate(embedding_error);
+    }
+
+    /// Look up the vectors of the words of a message, storing their ids to update the same rows
+    /// in `back_propagate_embedding`
+    pub(crate) fn embed(&mut self, value: MessageInput) -> Array2<F> {
         self.input = value;
-    
+        self.embedding.forward_propagate(self.sequence_words(&self.input))
+    }
+
+    /// Encode the word vectors of a message into an author embedding
+    pub(crate) fn encode_embedded(&mut self, embedded: Array2<F>) -> Array1<F> {
+        self.encode_after_embedding(embedded).mean_axis(Axis(0)).unwrap()
+    }
+
+    /// Back propagate the error of the author embedding from the last call to `encode_embedded`,
+    /// returning the error of the word vectors
+    pub(crate) fn back_propagate_embedded(&mut self, error: Array1<F>) -> Array2<F> {
+        // Mean-pooling spreads the error evenly over every word
+        let rows = F::constant(self.sequence_length);
+        let encoder_error = Array2::from_shape_fn((self.sequence_length, self.dimensionality), |(_, j)| error[j] / rows);
+        self.back_propagate_after_embedding(encoder_error)
+    }
+
+    /// Update the vectors of the words from the last call to `embed`
+    pub(crate) fn back_propagate_embedding(&mut self, error: Array2<F>) {
+        self.embedding.back_propagate(error);
+    }
+
+    /// Copy the transformer for a second pass through everything after the word embeddings.
+    /// The embedding and the tokenizer are the largest parts of the model, so they are left out.
+    pub(crate) fn copy_encoder(&mut self) -> Transformer<F> {
+        let embedding = std::mem::replace(&mut self.embedding, Embedding::new(HashMap::new(), &self.config));
+        let tokenizer = std::mem::take(&mut self.tokenizer);
+        let copy = self.clone();
+        self.embedding = embedding;
+        self.tokenizer = tokenizer;
+        copy
+    }
+
+    /// Forward propagate the current input through the embedding and the encoder stack
+    fn encode_sequence(&mut self) -> Array2<F> {
         // Convert input into embedded representation
-        let embedded = Array2::<f32>::from_shape_fn((self.num_words, self.dimensionality), |(i, j)| self.embedding[&self.input[i]][j]);
+        let embedded = self.embedding.forward_propagate(self.sequence_words(&self.input));
+        self.encode_after_embedding(embedded)
+    }
+
+    /// Forward propagate word vectors through the encoder stack
+    fn encode_after_embedding(&mut self, mut embedded: Array2<F>) -> Array2<F> {
+        // Mark which turn of the conversation each word belongs to
+        if let Some(segment_embedding) = self.segment_embedding.as_mut() {
+            embedded = segment_embedding.forward_propagate(embedded);
+        }
     
         // Apply positional encoding to the embedded representation
-        let mut enc_output = self.pos_encoder.forward_propagate(embedded);
+        let enc_output = self.pos_encoder.forward_propagate(embedded);
+        let mut enc_output = self.embedding_dropout.forward_propagate(enc_output);
 
         // Iterate through each encoder block and forward propagate the output
         for i in 0..self.params.encoder_blocks.len() {
             enc_output = self.params.encoder_blocks[i].forward_propagate(enc_output);
         }
+        if let Some(final_norm) = self.params.final_norm.as_mut() {
+            enc_output = final_norm.forward_propagate(enc_output);
+        }
+
+        enc_output
+    }
+
+    /// Run the input through the embedding and the encoder stack in evaluation mode
+    fn infer_sequence(&self, input: &MessageInput) -> Array2<F> {
+        let mut embedded = self.embedding.infer(self.sequence_words(input));
+        if let Some(segment_embedding) = self.segment_embedding.as_ref() {
+            embedded = segment_embedding.infer(embedded);
+        }
+
+        let mut enc_output = self.pos_encoder.infer(embedded);
+        for encoder_block in self.params.encoder_blocks.iter() {
+            enc_output = encoder_block.infer(enc_output);
+        }
+        if let Some(final_norm) = self.params.final_norm.as_ref() {
+            enc_output = final_norm.infer(enc_output);
+        }
+
+        enc_output
+    }
+
+    /// The input of the classifier in evaluation mode: the flattened encoder output followed by
+    /// the character and stylometric features
+    fn infer_features(&self, input: &MessageInput) -> Array1<F> {
+        let mut flat_output = self.infer_sequence(input).into_shape(self.sequence_length*self.dimensionality).unwrap();
+        if let Some(char_encoder) = self.char_encoder.as_ref() {
+            let char_features = char_encoder.infer(input.text.clone());
+            flat_output = concatenate(Axis(0), &[flat_output.view(), char_features.view()]).unwrap();
+        }
+        if self.config.stylometric_features {
+            flat_output = concatenate(Axis(0), &[flat_output.view(), input.features.mapv(cast).view()]).unwrap();
+        }
+
+        flat_output
+    }
+
+    /// Back propagate the error of the encoder output through the encoder stack and the embedding
+    fn back_propagate_sequence(&mut self, encoder_error: Array2<F>) {
+        let embedding_error = self.back_propagate_after_embedding(encoder_error);
+        self.embedding.back_propagate(embedding_error);
+    }
+
+    /// Back propagate the error of the encoder output through the encoder stack, returning the error of the word vectors
+    fn back_propagate_after_embedding(&mut self, mut encoder_error: Array2<F>) -> Array2<F> {
+        if let Some(final_norm) = self.params.final_norm.as_mut() {
+            encoder_error = final_norm.back_propagate(encoder_error);
+        }
+
+        // Iterate over the encoder blocks in reverse order and back propagate the encoder error
+        for i in (0..self.params.encoder_blocks.len()).rev() {
+            encoder_error = self.params.encoder_blocks[i].back_propagate(encoder_error);
+        }
+
+        // Back propagate through the embedding dropout and positional encoder to the embedding
+        let dropout_error = self.embedding_dropout.back_propagate(encoder_error);
+        let mut embedding_error = self.pos_encoder.back_propagate(dropout_error);
+        if let Some(segment_embedding) = self.segment_embedding.as_mut() {
+            embedding_error = segment_embedding.back_propagate(embedding_error);
+        }
+
+        embedding_error
+    }
+
+    /// Switch every dropout layer between training and evaluation mode
+    pub fn set_training(&mut self, training: bool) {
+        self.embedding_dropout.set_training(training);
+        for encoder_block in self.params.encoder_blocks.iter_mut() {
+            encoder_block.set_training(training);
+        }
+    }
+}
+
+impl<F: BlockFloat> Block for Transformer<F> {
+    type Input = MessageInput;
+    type Output = Array1<F>;
+
+    fn forward_propagate(&mut self, value: Self::Input) -> Self::Output {
+        self.input = value;
+        let enc_output = self.encode_sequence();
 
         // Flatten the output for classification
-        let flat_output = enc_output.clone().into_shape(self.num_words*self.dimensionality).unwrap();
+        let mut flat_output = enc_output.into_shape(self.sequence_length*self.dimensionality).unwrap();
+
+        // Concatenate the character features with the encoder output
+        if let Some(char_encoder) = self.char_encoder.as_mut() {
+            let char_features = char_encoder.forward_propagate(self.input.text.clone());
+            flat_output = concatenate(Axis(0), &[flat_output.view(), char_features.view()]).unwrap();
+        }
+
+        // Concatenate the stylometric features after the character features
+        if self.config.stylometric_features {
+            flat_output = concatenate(Axis(0), &[flat_output.view(), self.input.features.mapv(cast).view()]).unwrap();
+        }
     
         // Forward propagate the flattened output through the classifier
         self.output = self.classifier.forward_propagate(flat_output);
@@ -75,6 +389,10 @@ impl Block for Transformer {
         self.output.clone()
     }
 
+    fn infer(&self, value: Self::Input) -> Self::Output {
+        self.classifier.infer(self.infer_features(&value))
+    }
+
     /// Rather than giving an error here, input a desired value.
     fn back_propagate(&mut self, error: Self::Output) -> Self::Input {
         // Calculate the error of the last layer using the given error and the output of the neural network
@@ -83,17 +401,137 @@ impl Block for Transformer {
         // Back propagate the error to the classifier and get the classifier error
         let classifier_error = self.classifier.back_propagate(last_layer_error);
         
+        // Split off the error of the character features and back propagate it to the character encoder.
+        // The stylometric features at the end have no parameters, so their error is dropped.
+        let flat_size = self.sequence_length*self.dimensionality;
+        if let Some(char_encoder) = self.char_encoder.as_mut() {
+            let char_size = char_encoder.output_size();
+            char_encoder.back_propagate(classifier_error.slice(s![flat_size..flat_size + char_size]).to_owned());
+        }
+
         // Reshape the classifier error to match the shape of the encoder error
-        let mut encoder_error = classifier_error.into_shape((self.num_words, self.dimensionality)).unwrap();
+        let encoder_error = classifier_error.slice(s![..flat_size]).to_owned().into_shape((self.sequence_length, self.dimensionality)).unwrap();
+        self.back_propagate_sequence(encoder_error);
 
-        // Iterate over the encoder blocks in reverse order and back propagate the encoder error
-        for i in (0..self.params.encoder_blocks.len()).rev() {
-            encoder_error = self.params.encoder_blocks[i].back_propagate(encoder_error);
+        self.input.clone()
+    }
+}
+
+impl<F: BlockFloat> Transformer<F> {
+    /// The parameters of everything after the word embeddings, in the order of `parameters`
+    pub(crate) fn encoder_parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        self.collect_parameters(false)
+    }
+
+    /// The parameters of the whole model, or of everything after the word embeddings
+    fn collect_parameters(&mut self, with_embedding: bool) -> Vec<ArrayViewMutD<'_, F>> {
+        let mut params = if with_embedding { self.embedding.parameters() } else { Vec::new() };
+        if let Some(segment_embedding) = self.segment_embedding.as_mut() {
+            params.extend(segment_embedding.parameters());
+        }
+        params.extend(self.pos_encoder.parameters());
+        for encoder_block in self.params.encoder_blocks.iter_mut() {
+            params.extend(encoder_block.parameters());
+        }
+        if let Some(final_norm) = self.params.final_norm.as_mut() {
+            params.extend(final_norm.parameters());
+        }
+        if let Some(char_encoder) = self.char_encoder.as_mut() {
+            params.extend(char_encoder.parameters());
         }
+        params.extend(self.classifier.parameters());
+        params
+    }
+}
 
-        // The positional encoder doesn't have any trainable parameters
-        // self.pos_encoder.back_propagate(encoder_error);
+impl<F: BlockFloat> Parameters<F> for Transformer<F> {
+    fn parameters(&mut self) -> Vec<ArrayViewMutD<'_, F>> {
+        self.collect_parameters(true)
+    }
+}
 
-        arr1(&["".to_string()])
+/// Quantize the feed-forward, attention and classifier weights. The embeddings and the
+/// character encoder keep their float weights.
+impl<F: BlockFloat> Quantize for Transformer<F> {
+    fn quantize(&mut self) {
+        for encoder_block in self.params.encoder_blocks.iter_mut() {
+            encoder_block.quantize();
+        }
+        self.classifier.quantize();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use rand::Rng;
+    use super::Transformer;
+    use crate::block::Block;
+    use crate::char_encoder::CharEncoderConfig;
+    use crate::config::{NormPlacement, TransformerConfig};
+    use crate::embedding::Embedding;
+    use crate::positional_encoder::PositionalEncoding;
+    use chat_core::tokenizer::Tokenizer;
+
+    const WORDS: [&str; 8] = ["the", "cat", "sat", "on", "a", "mat", "and", "slept"];
+
+    /// Create a transformer with random embeddings for a handful of words
+    pub(crate) fn small_transformer(config: TransformerConfig) -> Transformer {
+        let mut rng = rand::thread_rng();
+        let vectors: HashMap<String, Vec<f32>> = WORDS.iter().map(|word| (word.to_string(), (0..config.dimensionality).map(|_| rng.gen::<f32>() - 0.5).collect())).collect();
+        let embedding = Embedding::new(vectors, &config);
+        Transformer::new(config, Tokenizer::default(), embedding)
+    }
+
+    /// Check that evaluation-mode inference gives the same output as a forward pass with dropout
+    /// disabled, for a message replying to the given number of previous turns
+    fn check_infer(config: TransformerConfig) {
+        let context_turns = config.context_turns;
+        let mut transformer = small_transformer(config);
+        transformer.set_training(false);
+
+        let previous = ["The cat sat on a mat.", "And then?"];
+        let input = transformer.message_input("The cat slept, on an unknown mat!", &previous[..context_turns]);
+        let inferred = transformer.infer(input.clone());
+        assert_eq!(inferred, transformer.forward_propagate(input));
+    }
+
+    #[test]
+    fn infer_matches_forward_propagate_post_norm() {
+        check_infer(TransformerConfig {
+            num_words: 6,
+            dimensionality: 8,
+            hidden_layer_size: 16,
+            ..TransformerConfig::default()
+        });
+    }
+
+    #[test]
+    fn infer_matches_forward_propagate_pre_norm_rotary() {
+        check_infer(TransformerConfig {
+            num_words: 6,
+            dimensionality: 8,
+            hidden_layer_size: 16,
+            norm_placement: NormPlacement::Pre,
+            positional_encoding: PositionalEncoding::Rotary,
+            char_encoder: Some(CharEncoderConfig { max_chars: 32, char_dimensionality: 4, num_filters: 4, kernel_size: 3 }),
+            stylometric_features: true,
+            context_turns: 1,
+            ..TransformerConfig::default()
+        });
+    }
+
+    #[test]
+    fn infer_matches_forward_propagate_alibi_with_context() {
+        check_infer(TransformerConfig {
+            num_words: 6,
+            dimensionality: 8,
+            num_heads: 2,
+            hidden_layer_size: 16,
+            positional_encoding: PositionalEncoding::Alibi,
+            char_encoder: Some(CharEncoderConfig { max_chars: 32, char_dimensionality: 4, num_filters: 4, kernel_size: 3 }),
+            context_turns: 2,
+            ..TransformerConfig::default()
+        });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+use word_embeddings::run::{build_co_occurrence_matrix, pca};
+use std::hint::black_box;
+
+const NUM_MESSAGES: usize = 20000;
+const WORDS_PER_MESSAGE: usize = 10;
+
+/// A synthetic dataset of messages drawn uniformly from a vocabulary of `vocab_size` words
+fn dataset(vocab_size: usize) -> (Vec<String>, Vec<Vec<String>>) {
+    let mut rng = rand::thread_rng();
+    let vocab: Vec<String> = (0..vocab_size).map(|i| format!("w{}", i)).collect();
+    let messages = (0..NUM_MESSAGES).map(|_| (0..WORDS_PER_MESSAGE).map(|_| vocab[rng.gen_range(0..vocab_size)].clone()).collect()).collect();
+    (vocab, messages)
+}
+
+fn embeddings(c: &mut Criterion) {
+    let mut group = c.benchmark_group("WordEmbeddings");
+    group.sample_size(10);
+
+    for vocab_size in [250, 500, 1000] {
+        let (vocab, messages) = dataset(vocab_size);
+        group.bench_with_input(BenchmarkId::new("co_occurrence", vocab_size), &messages, |b, messages| {
+            b.iter(|| build_co_occurrence_matrix(black_box(&vocab), black_box(messages)))
+        });
+
+        let matrix = build_co_occurrence_matrix(&vocab, &messages);
+        group.bench_with_input(BenchmarkId::new("pca", vocab_size), &matrix, |b, matrix| {
+            b.iter(|| pca(black_box(matrix.clone()), 64, 1))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, embeddings);
+criterion_main!(benches);
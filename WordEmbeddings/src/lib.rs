@@ -1 +1,5 @@
+// Link the BLAS library ndarray hands its matrix products to
+#[cfg(feature = "blas")]
+extern crate blas_src;
+
 pub mod run;
\ No newline at end of file
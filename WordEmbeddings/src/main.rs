@@ -1,5 +1,9 @@
 use word_embeddings::*;
+use chat_core::dataset::load_messages;
+use chat_core::normalise::Normaliser;
+use chat_core::tokenizer::{Tokenizer, TokenizerModel};
 use std::io;
+use std::path::Path;
 
 fn main() {
     println!("Enter the desired dimensionality: ");
@@ -22,5 +26,41 @@ fn main() {
     io::stdin().read_line(&mut input).expect("Failed to read input.");
     let num_threads = input.trim().parse().expect("Invalid input.");
 
-    run::run(dimensionality, file_name, num_threads);
+    println!("Tokenizer file to load or create (leave empty to split on words): ");
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).expect("Failed to read input.");
+    let tokenizer_name = input.trim();
+
+    let tokenizer = if tokenizer_name.is_empty() {
+        Tokenizer::default()
+    } else if Path::new(tokenizer_name).exists() {
+        Tokenizer::load(tokenizer_name)
+    } else {
+        println!("Normalisation (legacy or stylistic): ");
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).expect("Failed to read input.");
+        let normaliser = match input.trim() {
+            "legacy" => Normaliser::legacy(),
+            "stylistic" => Normaliser::stylistic(),
+            _ => panic!("Invalid input."),
+        };
+
+        println!("BPE vocabulary size (0 to split on words): ");
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).expect("Failed to read input.");
+        let vocab_size = input.trim().parse().expect("Invalid input.");
+
+        let tokenizer = if vocab_size == 0 {
+            Tokenizer::word(normaliser)
+        } else {
+            Tokenizer::train_bpe(normaliser, &load_messages("../train.json"), vocab_size)
+        };
+        if let TokenizerModel::Bpe(bpe) = &tokenizer.model {
+            println!("BPE vocab size: {}", bpe.vocab().len());
+        }
+        tokenizer.save(tokenizer_name);
+        tokenizer
+    };
+
+    run::run(dimensionality, file_name, num_threads, &tokenizer);
 }
@@ -1,65 +1,28 @@
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 use std::thread;
-use std::fs::File;
-use std::io::prelude::*;
 use rand::Rng;
-use serde::{Serialize, Deserialize};
-
-/// Clean the chat message by removing all non-alphanumeric characters
-/// and un-encoded words
-fn clean_msg(msg: String) -> String {
-    // "I love this chat! It's so good."
-    // => "i love this chat its so good "
-    let mut clean_msg: String = String::new();
-    
-    let chars: String = msg
-        .to_ascii_lowercase()
-        .chars()
-        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
-        .collect::<String>();
-    
-    let words = chars
-        .split_whitespace()
-        .collect::<Vec<&str>>();
-
-    for word in words {
-        clean_msg.push_str(word);
-        clean_msg.push(' ');
-    }
-
-    clean_msg.trim().to_string()
-
-    
-}
-
-pub fn load_chat_dataset(json_path: &str) -> Vec<String> {
-    let mut chat_dataset = Vec::new();
-    let file = std::fs::File::open(json_path).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let json: serde_json::Value = serde_json::from_reader(reader).unwrap();
-    let messages = json.as_array().unwrap();
-    for message in messages {
-        let chat_msg = clean_msg(message["content"].as_str().unwrap().to_string());
-        
-        chat_dataset.push(chat_msg);
-    }
+use ndarray::{s, Array1, Array2, Axis};
+use ndarray::linalg::general_mat_mul;
+use chat_core::dataset::load_messages;
+use chat_core::embedding_file::save_embeddings;
+use chat_core::tokenizer::Tokenizer;
+
+pub fn load_chat_dataset(json_path: &str, tokenizer: &Tokenizer) -> Vec<Vec<String>> {
+    let chat_dataset: Vec<Vec<String>> = load_messages(json_path).iter().map(|msg| tokenizer.tokenize(msg)).collect();
     println!("Chat dataset size: {}", chat_dataset.len());
     chat_dataset
 }
 
-fn build_vocab(dataset: &Vec<String>) -> Vec<String> {
+/// The words which appear more than 20 times in the dataset
+pub fn build_vocab(dataset: &[Vec<String>]) -> Vec<String> {
     let mut vocab: Vec<String> = Vec::new();
-    let mut word_counts: HashMap<String, usize> = HashMap::new();
+    let mut word_counts: HashMap<&str, usize> = HashMap::new();
     for msg in dataset {
-        for word in msg.split_whitespace() {
-            if !word_counts.contains_key(&word.to_string()) {
-                word_counts.insert(word.to_string(), 0);
-            } else {
-                word_counts.insert(word.to_string(), word_counts[&word.to_string()]+1);
-            }
+        for word in msg {
+            let count = word_counts.entry(word).or_insert(0);
+            *count += 1;
             // Only accepts common words to keep size manageable 
-            if word_counts[&word.to_string()] == 20 {
+            if *count == 21 {
                 vocab.push(word.to_string());
             }
         }
@@ -69,91 +32,54 @@ fn build_vocab(dataset: &Vec<String>) -> Vec<String> {
     vocab
 }
 
-fn build_co_occurrence_matrix(vocab: &Vec<String>, imdb_dataset: &Vec<String>) -> Vec<Vec<f32>> {
-    let word_to_index: HashMap<String, usize> = vocab.iter().enumerate().map(|(i, x)| (x.to_string(), i)).collect();
+/// Count how often each pair of words in the vocabulary appear near each other
+pub fn build_co_occurrence_matrix(vocab: &[String], imdb_dataset: &[Vec<String>]) -> Vec<Vec<f32>> {
+    let word_to_index: HashMap<&str, usize> = vocab.iter().enumerate().map(|(i, x)| (x.as_str(), i)).collect();
     let co_occurrence_window = 4;
     let mut co_occurrence_matrix = vec![vec![0.0; vocab.len()]; vocab.len()];
     for msg in imdb_dataset {
-        let words = msg.split_whitespace();
-        let mut filtered_words: Vec<&str> = Vec::new();
-        for word in words {
-            if vocab.contains(&word.to_string()) {
-                filtered_words.push(word);
-            }
-        }
+        let filtered_words: Vec<usize> = msg.iter().filter_map(|word| word_to_index.get(word.as_str()).copied()).collect();
 
-        let mut index = 0;
-        for word in filtered_words.clone() {
-            let mut index2 = 0;
-            for word2 in filtered_words.clone() {
+        for (index, &word) in filtered_words.iter().enumerate() {
+            for (index2, &word2) in filtered_words.iter().enumerate() {
                 let dist = (index as i32 - index2 as i32).abs();
                 if dist <= co_occurrence_window {
                     let weight = 1.0 - (dist as f32 / co_occurrence_window as f32);
-                    co_occurrence_matrix[word_to_index[word]][word_to_index[word2]] += weight;
+                    co_occurrence_matrix[word][word2] += weight;
                 }
-                index2 += 1;
             }
-            index += 1;
         }
     }
     co_occurrence_matrix
 }
 
-fn power_iteration(cov: &mut Vec<Vec<f32>>, num_iterations: usize, num_eigenvectors: usize) -> Vec<Vec<f32>>{
+fn power_iteration(cov: &mut Array2<f32>, num_iterations: usize, num_eigenvectors: usize) -> Vec<Array1<f32>> {
     // Uses the power iteration algorithm to compute N eigenvectors.
 
-    let mut eigenvectors: Vec<Vec<f32>> = Vec::new();
+    let mut eigenvectors: Vec<Array1<f32>> = Vec::new();
 
     for _ in 0..num_eigenvectors {
         // Generate random vector
-        let mut eigenvector: Vec<f32> = vec![0.0; cov.len()];
         let mut rng = rand::thread_rng();
-        for i in 0..cov.len() {
-            eigenvector[i] = rng.gen();
-        }
+        let mut eigenvector: Array1<f32> = (0..cov.nrows()).map(|_| rng.gen()).collect();
 
         for _ in 0..num_iterations {
             // Calculate dot product of covariance matrix and eigenvector
-            let mut new_eigenvector: Vec<f32> = vec![0.0; cov.len()];
-            for j in 0..cov.len() {
-                for k in 0..cov.len() {
-                    new_eigenvector[j] += cov[j][k] * eigenvector[k]
-                }
-            }
-
-            // Calculate norm of result
-            let mut sq_sum = 0.0;
-            for j in 0..cov.len() {
-                sq_sum += f32::powi(new_eigenvector[j], 2);
-            }
-            let norm = f32::powf(sq_sum, 0.5);
+            let new_eigenvector = cov.dot(&eigenvector);
 
             // Normalise result
-            for j in 0..cov.len() {
-                eigenvector[j] = new_eigenvector[j] / norm;
-            }
+            let norm = new_eigenvector.dot(&new_eigenvector).sqrt();
+            eigenvector = new_eigenvector / norm;
         }
         // Calculate eigenvalue from eigenvector
-        let mut temp = vec![0.0; cov.len()];
-        for i in 0..cov.len() {
-            for j in 0..cov.len() {
-                temp[i] += cov[i][j] * eigenvector[j]
-            }
-        }
-
-        let mut eigenvalue = 0.0;
-        for i in 0..cov.len() {
-            eigenvalue += eigenvector[i] * temp[i];
-        }
-
-        eigenvectors.push(eigenvector.clone());
+        let eigenvalue = eigenvector.dot(&cov.dot(&eigenvector));
 
         // Redirect matrix to find next eigenvector
-        for i in 0..cov.len() {
-            for j in 0..cov.len() {
-                cov[i][j] -= eigenvalue * eigenvector[i] * eigenvector[j];
-            }
-        }
+        let column = eigenvector.view().insert_axis(Axis(1));
+        let row = eigenvector.view().insert_axis(Axis(0));
+        general_mat_mul(-eigenvalue, &column, &row, 1.0, cov);
+
+        eigenvectors.push(eigenvector);
     }
 
     eigenvectors
@@ -161,100 +87,49 @@ fn power_iteration(cov: &mut Vec<Vec<f32>>, num_iterations: usize, num_eigenvect
 
 /// Apply principal component analysis to 'matrix'. Generate new elements with
 /// a dimensionality of 'num_components'
-fn pca(mut matrix: Vec<Vec<f32>>, num_components: usize, num_threads: usize) -> Vec<Vec<f32>> {
-    // Normalise data
-    for i in 0..matrix.len() {
-        let mut total = 0.0;
-        for j in 0..matrix.len() {
-            total += matrix[i][j];
-        }
-        let mean = total / matrix.len() as f32;
-        let mut dists_from_mean = 0.0;
-        for j in 0..matrix[i].len() {
-            dists_from_mean += f32::powi(matrix[i][j] - mean, 2);
-        }
-        let stdev = f32::powf(dists_from_mean / matrix[i].len() as f32, 0.5);
-
-        for j in 0..matrix.len() {
-            matrix[i][j] = (matrix[i][j] - mean) / stdev;
-        }
-    }
-
-    // Find covariance matrix
+pub fn pca(matrix: Vec<Vec<f32>>, num_components: usize, num_threads: usize) -> Vec<Vec<f32>> {
     let matrix_len = matrix.len();
-    let matrix = Arc::new(matrix.clone());
-    let covariance_matrix = Arc::new(Mutex::new(vec![vec![0.0; matrix_len]; matrix_len]));
-
-    // Find the covariance matrix on multiple threads
-    let mut handles = vec![];
-    for i in 0..num_threads {
-        let matrix = Arc::clone(&matrix);
-        let covariance_matrix = Arc::clone(&covariance_matrix);
-        let col_per_thread = (matrix_len as f32 / num_threads as f32).ceil() as usize;
-        let handle = thread::spawn(move || {
-            for j in 0..col_per_thread {
-                let index = i * col_per_thread + j;
-                if index < matrix_len {
-                    let mut inner_vector = vec![0.0; matrix_len];
-                    for k in 0..matrix_len {
-                        let mut sum = 0.0;
-                        for l in 0..matrix_len {
-                            sum += matrix[index][l] * matrix[k][l];
-                        }
-                        let covariance = sum / matrix_len as f32;
-                        inner_vector[k] = covariance;
-                    }
+    let mut matrix = Array2::from_shape_vec((matrix_len, matrix_len), matrix.into_iter().flatten().collect()).unwrap();
 
-                    let mut cov_lock = covariance_matrix.lock().unwrap();
-                    for k in 0..matrix_len {
-                        cov_lock[index][k] = inner_vector[k];
-                    }
-                }
-            }
-        });
-        handles.push(handle);
+    // Normalise data
+    for mut row in matrix.axis_iter_mut(Axis(0)) {
+        let mean = row.mean().unwrap();
+        let stdev = row.std(0.0);
+        row.mapv_inplace(|x| (x - mean) / stdev);
     }
 
-    // Wait for all threads to finish
-    for handle in handles {
-        handle.join().unwrap();
-    }
+    // Find the covariance matrix on multiple threads, each multiplying a block of rows
+    let mut covariance_matrix = Array2::<f32>::zeros((matrix_len, matrix_len));
+    let rows_per_thread = matrix_len.div_ceil(num_threads).max(1);
+    thread::scope(|scope| {
+        let matrix = &matrix;
+        for (i, mut block) in covariance_matrix.axis_chunks_iter_mut(Axis(0), rows_per_thread).enumerate() {
+            scope.spawn(move || {
+                let start = i * rows_per_thread;
+                let rows = matrix.slice(s![start..start + block.nrows(), ..]);
+                general_mat_mul(1.0 / matrix_len as f32, &rows, &matrix.t(), 0.0, &mut block);
+            });
+        }
+    });
 
     // Compute eigenvectors and eigenvalues
-    let mut cov_lock = covariance_matrix.lock().unwrap();
-    let eigenvectors = power_iteration(&mut cov_lock, 10, num_components);
+    let eigenvectors = power_iteration(&mut covariance_matrix, 10, num_components);
 
-    let mut reduced: Vec<Vec<f32>> = Vec::new();
-    for i in 0..matrix.len() {
-        let mut embedding: Vec<f32> = Vec::new();
-        for j in 0..num_components {
-            let mut component: f32 = 0.0;
-            for k in 0..matrix.len() {
-                component += matrix[i][k] * eigenvectors[j][k]
-            }
-            embedding.push(component);
-        }
-        reduced.push(embedding);
+    // Project each row onto the eigenvectors
+    let mut components = Array2::<f32>::zeros((matrix_len, eigenvectors.len()));
+    for (mut column, eigenvector) in components.axis_iter_mut(Axis(1)).zip(&eigenvectors) {
+        column.assign(eigenvector);
     }
+    let reduced = matrix.dot(&components);
 
-    reduced
+    reduced.outer_iter().map(|row| row.to_vec()).collect()
 }
 
-#[derive(Serialize, Deserialize)]
-struct WordEmbeddings {
-    data: HashMap<String, Vec<f32>>
-}
-
-pub fn run(dimensionality: usize, output_name: &str, num_threads: usize) {
-    let imdb_dataset = load_chat_dataset("../train.json");
+pub fn run(dimensionality: usize, output_name: &str, num_threads: usize, tokenizer: &Tokenizer) {
+    let imdb_dataset = load_chat_dataset("../train.json", tokenizer);
     let vocab = build_vocab(&imdb_dataset);
     let co_occurrence_matrix = build_co_occurrence_matrix(&vocab, &imdb_dataset);
     let reduced = pca(co_occurrence_matrix, dimensionality, num_threads);
-    let mut word_embeddings = WordEmbeddings {data: HashMap::new()};
-    for i in 0..vocab.len() {
-        word_embeddings.data.insert(vocab[i].clone(), reduced[i].clone());
-    }
-    let serialized = serde_json::to_string(&word_embeddings).unwrap();
-    let mut file = File::create(output_name).expect("Unable to create file");
-    file.write_all(serialized.as_bytes()).expect("Unable to write");
+    let word_embeddings: HashMap<String, Vec<f32>> = vocab.into_iter().zip(reduced).collect();
+    save_embeddings(output_name, word_embeddings);
 }
@@ -6,8 +6,14 @@ data = pd.read_parquet(path)
 df = pd.DataFrame(data)
 json_data = []
 for i in range(len(df)):
-    json_data.append(df["conversation_b"][i][0])
-    json_data.append(df["conversation_b"][i][1])
+    # Keep every turn of the conversation, recording where it came from
+    for turn, message in enumerate(df["conversation_b"][i]):
+        json_data.append({
+            "content": message["content"],
+            "role": message["role"],
+            "conversation_id": df["question_id"][i],
+            "turn": turn,
+        })
 
 with open("train.json", "w") as f:
-    json.dump(json_data, f)
\ No newline at end of file
+    json.dump(json_data, f)